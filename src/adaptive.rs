@@ -0,0 +1,121 @@
+use std::ops::RangeInclusive;
+
+/// Moves capacity between the small and main queues, based on which one looks too small.
+///
+/// A ghost hit is a key which was evicted from the small queue before its second access came,
+/// so the small queue should have held it for longer. A reinsertion in the main queue is an
+/// entry which was still being accessed when it got to the tail, so the main queue is earning
+/// its space. Each is taken as a fraction of the evictions from its queue, and over each epoch,
+/// whichever is higher wins a step of capacity for its queue.
+pub(crate) struct Adaptive {
+    bounds: RangeInclusive<usize>,
+    ghost_hits: u64,
+    small_evictions: u64,
+    reinsertions: u64,
+    main_evictions: u64,
+    inserts: usize,
+}
+
+impl Adaptive {
+    pub fn new(bounds: RangeInclusive<usize>) -> Self {
+        Self {
+            bounds,
+            ghost_hits: 0,
+            small_evictions: 0,
+            reinsertions: 0,
+            main_evictions: 0,
+            inserts: 0,
+        }
+    }
+
+    pub fn ghost_hit(&mut self) {
+        self.ghost_hits += 1;
+    }
+
+    /// Count an entry leaving the small queue for the ghost queue.
+    pub fn small_eviction(&mut self) {
+        self.small_evictions += 1;
+    }
+
+    pub fn reinsertion(&mut self) {
+        self.reinsertions += 1;
+    }
+
+    pub fn main_eviction(&mut self) {
+        self.main_evictions += 1;
+    }
+
+    /// Count an insertion of a new key, and at the end of an epoch of `len` of them, return the
+    /// size the small queue should have out of `total`, if it should change.
+    pub fn insert(&mut self, len: usize, small: usize, total: usize) -> Option<usize> {
+        self.inserts += 1;
+        if self.inserts < len {
+            return None;
+        }
+        // Compare ghost_hits / small_evictions with reinsertions / main tail visits, without
+        // dividing.
+        let small_demand = self.ghost_hits * (self.reinsertions + self.main_evictions).max(1);
+        let main_demand = self.reinsertions * self.small_evictions.max(1);
+        let step = (total / 100).max(1);
+        let max = (*self.bounds.end()).min(total);
+        let min = (*self.bounds.start()).min(max);
+        let target = if small_demand > main_demand {
+            small.saturating_add(step)
+        } else if main_demand > small_demand {
+            small.saturating_sub(step)
+        } else {
+            small
+        };
+        *self = Self::new(self.bounds.clone());
+        Some(target.clamp(min, max)).filter(|&target| target != small)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::S3Fifo;
+
+    fn access(cache: &mut S3Fifo<u32, u32>, key: u32) {
+        if cache.read(&key).is_none() {
+            cache.insert(key, key);
+        }
+        cache.check_invariants();
+    }
+
+    #[test]
+    fn converges() {
+        let build = || S3Fifo::builder().capacity(100).adaptive(0.05, 0.5).build().unwrap();
+
+        // Every key comes back once, 15 insertions later. The small queue has to grow for the
+        // second access to find it there.
+        let mut cache = build();
+        for i in 0 .. 2000 {
+            access(&mut cache, i);
+            if i >= 15 {
+                access(&mut cache, i - 15);
+            }
+        }
+        let small = cache.stats().small_size;
+        assert!((15 ..= 50).contains(&small), "{small}");
+        assert_eq!(cache.stats().main_size, 100 - small);
+
+        // A hot set which only fits in main if it gets more of the capacity, mixed with keys seen
+        // only once.
+        let mut cache = S3Fifo::builder()
+            .capacity(100)
+            .small_ratio(0.5)
+            .adaptive(0.05, 0.5)
+            .build()
+            .unwrap();
+        for i in 0 .. 20_000 {
+            access(&mut cache, i % 70);
+            if i % 3 == 0 {
+                access(&mut cache, 1000 + i);
+            }
+        }
+        // It stops once the hot set fits, and there are no more evictions from main.
+        assert_eq!(cache.stats().small_size, 30);
+
+        assert!(S3Fifo::<u32, u32>::builder().adaptive(0.5, 0.2).build().is_err());
+    }
+}
@@ -0,0 +1,405 @@
+//! Replays request traces through `S3Fifo` at one or more cache sizes, and reports how it did.
+
+use std::io;
+use std::process::ExitCode;
+
+use s3fifo::policy::{ArcCache, ClockCache, FifoCache, LruCache, OptCache, SieveCache};
+use s3fifo::{CachePolicy, MissRatioCurve, S3Fifo, Stats};
+
+mod trace;
+
+use trace::{Format, Op, Request, Source};
+
+const USAGE: &str = "\
+usage: s3fifo-sim [options] <trace> <size>...
+       s3fifo-sim [options] --workload <spec> <size>...
+
+Replays a trace, or a synthetic workload, through an S3-FIFO cache of each size, and prints the
+miss ratios and queue statistics.
+
+options:
+  --policy <name>        s3fifo (the default), lru, fifo, clock, sieve, arc, or opt, which are
+                         only given sizes in numbers of objects
+  --format <csv|oracle>  trace format; by default, csv for .csv files, oracleGeneral otherwise
+  --bytes                sizes are in bytes, and objects weigh their size; by default, sizes
+                         are numbers of objects
+  --small-ratio <r>      fraction of the cache for the small queue (default 0.1)
+  --mrc <csv|json>       estimate a miss ratio curve in one pass over the trace, by simulating
+                         a sample of the keys in scaled down caches, and print it as csv or json
+  --sample-rate <r>      fraction of the keys sampled for --mrc (default 0.01)
+  --points <n>           with two sizes, use n sizes between them, evenly spaced on a log scale
+  --workload <spec>      generate requests instead of reading a trace, from one of
+                           zipf:keys=<n>,skew=<s>
+                           one-hit-wonders:keys=<n>,skew=<s>,fraction=<f>
+                           scan:hot=<n>,skew=<s>,scan=<n>,period=<n>
+                           loop:keys=<n>
+                           shifting:keys=<n>,skew=<s>,period=<n>
+                         where the skew defaults to 1, and the fraction to 0.5
+  --requests <n>         number of requests to generate (default 1000000)
+  --seed <n>             seed for generating requests (default 0)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Policy {
+    S3Fifo,
+    Lru,
+    Fifo,
+    Clock,
+    Sieve,
+    Arc,
+    Opt,
+}
+
+impl Policy {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "s3fifo" => Policy::S3Fifo,
+            "lru" => Policy::Lru,
+            "fifo" => Policy::Fifo,
+            "clock" => Policy::Clock,
+            "sieve" => Policy::Sieve,
+            "arc" => Policy::Arc,
+            "opt" => Policy::Opt,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Output {
+    Table,
+    Csv,
+    Json,
+}
+
+struct Options {
+    source: Source,
+    policy: Policy,
+    sizes: Vec<usize>,
+    bytes: bool,
+    small_ratio: f64,
+    output: Output,
+    sample_rate: f64,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut args = args.into_iter();
+    let mut format = None;
+    let mut policy = Policy::S3Fifo;
+    let mut bytes = false;
+    let mut small_ratio = 0.1;
+    let mut output = Output::Table;
+    let mut sample_rate = 0.01;
+    let mut points = None;
+    let mut workload = None;
+    let mut requests = 1_000_000;
+    let mut seed = 0;
+    let mut positional = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                let name = args.next().ok_or("--format needs a value")?;
+                format = Some(Format::parse(&name).ok_or(format!("unknown format {name:?}"))?);
+            }
+            "--policy" => {
+                let name = args.next().ok_or("--policy needs a value")?;
+                policy = Policy::parse(&name).ok_or(format!("unknown policy {name:?}"))?;
+            }
+            "--bytes" => bytes = true,
+            "--small-ratio" => {
+                let ratio = args.next().ok_or("--small-ratio needs a value")?;
+                small_ratio = ratio.parse().map_err(|_| format!("bad ratio {ratio:?}"))?;
+            }
+            "--mrc" => {
+                output = match args.next().as_deref() {
+                    Some("csv") => Output::Csv,
+                    Some("json") => Output::Json,
+                    _ => return Err("--mrc needs csv or json".to_string()),
+                };
+            }
+            "--sample-rate" => {
+                let rate = args.next().ok_or("--sample-rate needs a value")?;
+                sample_rate = rate
+                    .parse()
+                    .ok()
+                    .filter(|rate| *rate > 0.0 && *rate <= 1.0)
+                    .ok_or(format!("bad sample rate {rate:?}"))?;
+            }
+            "--points" => {
+                let n = args.next().ok_or("--points needs a value")?;
+                points = Some(n.parse().map_err(|_| format!("bad number of points {n:?}"))?);
+            }
+            "--workload" => {
+                let spec = args.next().ok_or("--workload needs a value")?;
+                workload = Some(trace::parse_workload(&spec)?);
+            }
+            "--requests" => {
+                let n = args.next().ok_or("--requests needs a value")?;
+                requests = n.parse().map_err(|_| format!("bad number of requests {n:?}"))?;
+            }
+            "--seed" => {
+                let n = args.next().ok_or("--seed needs a value")?;
+                seed = n.parse().map_err(|_| format!("bad seed {n:?}"))?;
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {arg}")),
+            _ => positional.push(arg),
+        }
+    }
+    let mut positional = positional.into_iter();
+    let source = match workload {
+        Some(workload) => Source::Workload { workload, requests, seed },
+        None => {
+            let path = positional.next().ok_or("no trace given")?;
+            let format = format.unwrap_or_else(|| Format::guess(&path));
+            Source::File { path, format }
+        }
+    };
+    let mut sizes = positional
+        .map(|size| size.parse().map_err(|_| format!("bad size {size:?}")))
+        .collect::<Result<Vec<_>, _>>()?;
+    if sizes.is_empty() {
+        return Err("no cache size given".to_string());
+    }
+    if policy != Policy::S3Fifo && (bytes || output != Output::Table) {
+        return Err("--bytes and --mrc only work with s3fifo".to_string());
+    }
+    if let Some(points) = points {
+        let [min, max] = sizes[..] else {
+            return Err("--points needs two sizes".to_string());
+        };
+        sizes = MissRatioCurve::log_spaced_sizes(min, max, points);
+    }
+    Ok(Options {
+        source,
+        policy,
+        sizes,
+        bytes,
+        small_ratio,
+        output,
+        sample_rate,
+    })
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Report {
+    gets: u64,
+    misses: u64,
+    bytes: u64,
+    missed_bytes: u64,
+    // Only S3-FIFO has queues to count.
+    stats: Option<Stats>,
+}
+
+impl Report {
+    fn miss_ratio(&self) -> f64 {
+        self.misses as f64 / self.gets.max(1) as f64
+    }
+
+    fn byte_miss_ratio(&self) -> f64 {
+        self.missed_bytes as f64 / self.bytes.max(1) as f64
+    }
+}
+
+/// Replay a trace through a cache, inserting what's missing on every get.
+fn replay(trace: &[Request], cache: &mut impl CachePolicy<u64, u32>) -> Report {
+    let mut report = Report::default();
+    for request in trace {
+        match request.op {
+            Op::Get => {
+                report.gets += 1;
+                report.bytes += u64::from(request.size);
+                if cache.get(&request.key).is_none() {
+                    report.misses += 1;
+                    report.missed_bytes += u64::from(request.size);
+                    cache.insert(request.key, request.size);
+                }
+            }
+            Op::Set => {
+                cache.insert(request.key, request.size);
+            }
+            Op::Delete => {
+                cache.remove(&request.key);
+            }
+        }
+    }
+    report
+}
+
+fn simulate(trace: &[Request], size: usize, options: &Options) -> Result<Report, String> {
+    Ok(match options.policy {
+        Policy::S3Fifo => {
+            let builder = S3Fifo::builder()
+                .capacity(size)
+                .small_ratio(options.small_ratio)
+                .stats(true);
+            let builder = if options.bytes {
+                builder.weigher(|_: &u64, size: &u32| *size as usize)
+            } else {
+                builder
+            };
+            let mut cache = builder.build().map_err(|e| e.to_string())?;
+            let report = replay(trace, &mut cache);
+            Report { stats: Some(cache.stats()), ..report }
+        }
+        Policy::Lru => replay(trace, &mut LruCache::new(size)),
+        Policy::Fifo => replay(trace, &mut FifoCache::new(size)),
+        Policy::Clock => replay(trace, &mut ClockCache::new(size)),
+        Policy::Sieve => replay(trace, &mut SieveCache::new(size)),
+        Policy::Arc => replay(trace, &mut ArcCache::new(size)),
+        Policy::Opt => {
+            let gets = trace.iter().filter(|request| request.op == Op::Get);
+            replay(trace, &mut OptCache::new(size, gets.map(|request| request.key)))
+        }
+    })
+}
+
+/// Estimate the miss ratio curve over the sizes, streaming the trace rather than loading it.
+fn miss_ratio_curve(options: &Options) -> Result<MissRatioCurve, String> {
+    let (bytes, small_ratio) = (options.bytes, options.small_ratio);
+    let mut mrc = MissRatioCurve::with_builder(options.sizes.clone(), options.sample_rate, |size| {
+        let builder = S3Fifo::builder().capacity(size).small_ratio(small_ratio);
+        if bytes {
+            builder.weigher(|_: &u64, size: &u32| *size as usize)
+        } else {
+            builder
+        }
+    })
+    .map_err(|e| e.to_string())?;
+    options.source.scan(|request| match request.op {
+        Op::Get => mrc.access(&request.key, request.size),
+        Op::Set => mrc.insert(&request.key, request.size),
+        Op::Delete => mrc.remove(&request.key),
+    })?;
+    Ok(mrc)
+}
+
+fn run(options: Options) -> Result<(), String> {
+    if options.output != Output::Table {
+        let mrc = miss_ratio_curve(&options)?;
+        let result = match options.output {
+            Output::Csv => mrc.write_csv(io::stdout().lock()),
+            _ => mrc.write_json(io::stdout().lock()),
+        };
+        return result.map_err(|e| e.to_string());
+    }
+
+    let trace = options.source.read()?;
+    eprintln!("{}: {} requests", options.source.name(), trace.len());
+
+    // Each size is simulated on its own thread.
+    let reports = std::thread::scope(|s| {
+        let handles: Vec<_> = options
+            .sizes
+            .iter()
+            .map(|&size| {
+                let trace = &trace;
+                let options = &options;
+                s.spawn(move || simulate(trace, size, options))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("simulation panicked"))
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+
+    print!("{:>12} {:>10} {:>10}", "size", "miss", "byte miss");
+    if options.policy == Policy::S3Fifo {
+        print!(
+            " {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "ghost hit", "promoted", "demoted", "reinsert", "evicted", "small/main",
+        );
+    }
+    println!();
+    for (size, report) in options.sizes.iter().zip(reports) {
+        print!("{:>12} {:>10.4} {:>10.4}", size, report.miss_ratio(), report.byte_miss_ratio());
+        match report.stats {
+            Some(stats) => println!(
+                " {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                stats.ghost_hits,
+                stats.promotions,
+                stats.demotions,
+                stats.reinsertions,
+                stats.evictions,
+                format!("{}/{}", stats.small_size, stats.main_size),
+            ),
+            None => println!(),
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let result = parse_args(std::env::args().skip(1)).and_then(run);
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("{message}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Result<Options, String> {
+        parse_args(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn arguments() {
+        let format = |options: &Options| match options.source {
+            Source::File { format, .. } => Some(format),
+            Source::Workload { .. } => None,
+        };
+        let options = args("--bytes trace.csv 10 20").unwrap();
+        assert_eq!(format(&options), Some(Format::Csv));
+        assert_eq!((options.sizes, options.bytes), (vec![10, 20], true));
+        let options = args("--format csv --small-ratio 0.2 trace.bin 5").unwrap();
+        assert_eq!((format(&options), options.small_ratio), (Some(Format::Csv), 0.2));
+        let options = args("--workload loop:keys=10 --requests 5 10 20").unwrap();
+        assert_eq!((format(&options), options.sizes), (None, vec![10, 20]));
+        assert_eq!(args("trace.bin").err().unwrap(), "no cache size given");
+        assert_eq!(args("--what trace 1").err().unwrap(), "unknown option --what");
+
+        let options = args("--mrc json --sample-rate 0.1 --points 3 trace 10 1000").unwrap();
+        assert_eq!((options.output, options.sample_rate), (Output::Json, 0.1));
+        assert_eq!(options.sizes, [10, 100, 1000]);
+        assert_eq!(args("--points 3 trace 10").err().unwrap(), "--points needs two sizes");
+        assert!(args("--sample-rate 0 trace 10").is_err());
+        assert_eq!(args("--policy sieve trace 10").unwrap().policy, Policy::Sieve);
+        assert!(args("--policy arc --bytes trace 10").is_err());
+    }
+
+    #[test]
+    fn replay() {
+        let get = |key, size| Request { key, size, op: Op::Get };
+        let trace = [
+            get(1, 10),
+            get(2, 30),
+            get(1, 10),
+            Request { key: 2, size: 30, op: Op::Delete },
+            get(2, 30),
+        ];
+        let report = simulate(&trace, 10, &args("--small-ratio 0.5 trace 10").unwrap()).unwrap();
+        assert_eq!((report.gets, report.misses), (4, 3));
+        assert_eq!((report.bytes, report.missed_bytes), (80, 70));
+        assert_eq!(report.stats.unwrap().inserts, 3);
+
+        // By bytes, 2 is too big for the cache.
+        let options = args("--bytes --small-ratio 0.5 trace 20").unwrap();
+        let report = simulate(&trace, 20, &options).unwrap();
+        assert_eq!((report.misses, report.missed_bytes), (3, 70));
+        assert_eq!(report.stats.unwrap().inserts, 1);
+
+        assert!(simulate(&trace, 10, &args("--small-ratio 2 trace 10").unwrap()).is_err());
+
+        // The other policies have no stats, and OPT can't do better.
+        let report = simulate(&trace, 1, &args("--policy lru trace 1").unwrap()).unwrap();
+        assert_eq!((report.misses, report.stats), (4, None));
+        let report = simulate(&trace, 1, &args("--policy opt trace 1").unwrap()).unwrap();
+        assert_eq!(report.misses, 3);
+    }
+}
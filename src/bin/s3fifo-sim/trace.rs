@@ -0,0 +1,265 @@
+//! Reading request traces, or generating them.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Read};
+
+use s3fifo::workload::Workload;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Get,
+    Set,
+    Delete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request {
+    pub key: u64,
+    pub size: u32,
+    pub op: Op,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Lines of `timestamp,key,size,op`, where the size and op can be left out, and default to
+    /// 1 and `get`. A header line is skipped.
+    Csv,
+    /// libCacheSim's oracleGeneral format: packed little-endian records of a u32 timestamp, u64
+    /// object id, u32 size and i64 next access time. Every request is a get.
+    OracleGeneral,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "csv" => Some(Format::Csv),
+            "oracle" | "oracleGeneral" => Some(Format::OracleGeneral),
+            _ => None,
+        }
+    }
+
+    /// Guess the format of a trace from its file name.
+    pub fn guess(path: &str) -> Self {
+        if path.ends_with(".csv") {
+            Format::Csv
+        } else {
+            Format::OracleGeneral
+        }
+    }
+
+    /// Pass each request to `f` as it's read, without holding the whole trace in memory.
+    pub fn scan(self, input: impl BufRead, f: impl FnMut(Request)) -> io::Result<()> {
+        match self {
+            Format::Csv => scan_csv(input, f),
+            Format::OracleGeneral => scan_oracle_general(input, f),
+        }
+    }
+}
+
+/// Where the requests come from.
+pub enum Source {
+    File { path: String, format: Format },
+    /// A synthetic workload, of gets for objects of size 1.
+    Workload { workload: Workload, requests: u64, seed: u64 },
+}
+
+impl Source {
+    pub fn name(&self) -> String {
+        match self {
+            Source::File { path, .. } => path.clone(),
+            Source::Workload { workload, .. } => format!("{workload:?}"),
+        }
+    }
+
+    pub fn read(&self) -> Result<Vec<Request>, String> {
+        let mut requests = vec![];
+        self.scan(|request| requests.push(request))?;
+        Ok(requests)
+    }
+
+    pub fn scan(&self, mut f: impl FnMut(Request)) -> Result<(), String> {
+        match self {
+            Source::File { path, format } => {
+                let file = File::open(path).map_err(|e| format!("{path}: {e}"))?;
+                format.scan(BufReader::new(file), f).map_err(|e| format!("{path}: {e}"))
+            }
+            Source::Workload { workload, requests, seed } => {
+                for key in workload.generate(*seed).take(*requests as usize) {
+                    f(Request { key, size: 1, op: Op::Get });
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Parse a workload given as its name and parameters, like `zipf:keys=1000,skew=0.8`. The skew
+/// defaults to 1, and the fraction of one-hit wonders to 0.5.
+pub fn parse_workload(spec: &str) -> Result<Workload, String> {
+    let (name, params) = spec.split_once(':').unwrap_or((spec, ""));
+    let mut values = HashMap::new();
+    for param in params.split(',').filter(|param| !param.is_empty()) {
+        let (key, value) = param.split_once('=').ok_or(format!("bad parameter {param:?}"))?;
+        let value: f64 = value.parse().map_err(|_| format!("bad value for {key}"))?;
+        values.insert(key, value);
+    }
+    let mut get = |key: &str, default: Option<f64>| {
+        values.remove(key).or(default).ok_or(format!("{name} needs {key}"))
+    };
+    let workload = match name {
+        "zipf" => Workload::Zipf {
+            keys: get("keys", None)? as u64,
+            skew: get("skew", Some(1.0))?,
+        },
+        "one-hit-wonders" => Workload::OneHitWonders {
+            keys: get("keys", None)? as u64,
+            skew: get("skew", Some(1.0))?,
+            fraction: get("fraction", Some(0.5))?,
+        },
+        "scan" => Workload::Scan {
+            hot: get("hot", None)? as u64,
+            skew: get("skew", Some(1.0))?,
+            scan: get("scan", None)? as u64,
+            period: get("period", None)? as u64,
+        },
+        "loop" => Workload::Loop { keys: get("keys", None)? as u64 },
+        "shifting" => Workload::Shifting {
+            keys: get("keys", None)? as u64,
+            skew: get("skew", Some(1.0))?,
+            period: get("period", None)? as u64,
+        },
+        _ => return Err(format!("unknown workload {name:?}")),
+    };
+    match values.keys().next() {
+        Some(key) => Err(format!("{name} has no parameter {key}")),
+        None => Ok(workload),
+    }
+}
+
+fn scan_csv(input: impl BufRead, mut f: impl FnMut(Request)) -> io::Result<()> {
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_csv_line(line) {
+            Some(request) => f(request),
+            // Allow a header.
+            None if i == 0 => {}
+            None => {
+                let message = format!("line {}: can't parse {line:?}", i + 1);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_csv_line(line: &str) -> Option<Request> {
+    let mut fields = line.split(',').map(str::trim);
+    let _timestamp = fields.next()?;
+    let key = fields.next().filter(|key| !key.is_empty())?;
+    let size = match fields.next() {
+        Some(size) => size.parse().ok()?,
+        None => 1,
+    };
+    let op = match fields.next().map(str::to_ascii_lowercase).as_deref() {
+        None | Some("get" | "read") => Op::Get,
+        Some("set" | "write" | "put") => Op::Set,
+        Some("delete" | "del" | "remove") => Op::Delete,
+        Some(_) => return None,
+    };
+    // Numeric keys are used as they are, so they match oracleGeneral object ids.
+    let key = key.parse().unwrap_or_else(|_| {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    });
+    Some(Request { key, size, op })
+}
+
+fn scan_oracle_general(mut input: impl Read, mut f: impl FnMut(Request)) -> io::Result<()> {
+    let mut record = [0; 24];
+    loop {
+        match input.read_exact(&mut record) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        f(Request {
+            key: u64::from_le_bytes(record[4 .. 12].try_into().unwrap()),
+            size: u32::from_le_bytes(record[12 .. 16].try_into().unwrap()),
+            op: Op::Get,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(format: Format, input: &[u8]) -> io::Result<Vec<Request>> {
+        let mut requests = vec![];
+        format.scan(input, |request| requests.push(request))?;
+        Ok(requests)
+    }
+
+    #[test]
+    fn csv() {
+        let trace = "timestamp,key,size,op\n1,10,100,get\n2,abc,5,SET\n\n3,10\n4,abc,5,delete\n";
+        let requests = read(Format::Csv, trace.as_bytes()).unwrap();
+        let ops: Vec<_> = requests.iter().map(|r| (r.size, r.op)).collect();
+        assert_eq!(ops, [(100, Op::Get), (5, Op::Set), (1, Op::Get), (5, Op::Delete)]);
+        assert_eq!(requests[0].key, 10);
+        assert_eq!(requests[1].key, requests[3].key);
+
+        let err = read(Format::Csv, b"1,a,1\n2,b,x\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: can't parse \"2,b,x\"");
+    }
+
+    #[test]
+    fn workloads() {
+        assert_eq!(
+            parse_workload("zipf:keys=100").unwrap(),
+            Workload::Zipf { keys: 100, skew: 1.0 }
+        );
+        assert_eq!(
+            parse_workload("scan:hot=10,scan=5,period=50,skew=0.5").unwrap(),
+            Workload::Scan { hot: 10, skew: 0.5, scan: 5, period: 50 }
+        );
+        assert_eq!(parse_workload("loop").unwrap_err(), "loop needs keys");
+        assert_eq!(parse_workload("loop:keys=5,skew=1").unwrap_err(), "loop has no parameter skew");
+        assert!(parse_workload("zipf:keys").is_err());
+
+        let workload = Workload::Loop { keys: 2 };
+        let source = Source::Workload { workload, requests: 3, seed: 0 };
+        let keys: Vec<_> = source.read().unwrap().iter().map(|request| request.key).collect();
+        assert_eq!(keys, [0, 1, 0]);
+    }
+
+    #[test]
+    fn oracle_general() {
+        let mut trace = vec![];
+        for (time, id, size) in [(1u32, 7u64, 100u32), (2, 8, 200)] {
+            trace.extend(time.to_le_bytes());
+            trace.extend(id.to_le_bytes());
+            trace.extend(size.to_le_bytes());
+            trace.extend((-1i64).to_le_bytes());
+        }
+        // A truncated record at the end is ignored.
+        trace.extend([0; 5]);
+        let requests = read(Format::OracleGeneral, &trace).unwrap();
+        assert_eq!(
+            requests,
+            [
+                Request { key: 7, size: 100, op: Op::Get },
+                Request { key: 8, size: 200, op: Op::Get },
+            ]
+        );
+    }
+}
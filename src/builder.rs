@@ -0,0 +1,320 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::hash::Hash;
+use std::time::Duration;
+
+use crate::{
+    Clock, EvictionListener, GhostQueue, List, S3Fifo, SystemClock, UnitWeigher, Weigher, MAX_FREQ,
+};
+
+/// Configures every knob of an [`S3Fifo`] cache.
+///
+/// The capacity is given as a total, which is split between the small and main queues by a
+/// ratio, by default the 10% the paper recommends for the small queue.
+///
+/// ```
+/// use s3fifo::S3Fifo;
+///
+/// let cache = S3Fifo::<u32, String>::builder()
+///     .capacity(1000)
+///     .small_ratio(0.2)
+///     .promotion_threshold(1)
+///     .build()
+///     .unwrap();
+/// ```
+pub struct S3FifoBuilder<K, V> {
+    capacity: usize,
+    small_ratio: f64,
+    max_freq: u8,
+    promotion_threshold: u8,
+    reset_on_promotion: bool,
+    ghost_capacity: Option<usize>,
+    ghost_fingerprints: bool,
+    adaptive: Option<(f64, f64)>,
+    weigher: Option<Box<dyn Weigher<K, V> + Send + Sync>>,
+    listener: Option<Box<dyn EvictionListener<K, V> + Send + Sync>>,
+    clock: Box<dyn Clock + Send + Sync>,
+    time_to_live: Option<Duration>,
+    time_to_idle: Option<Duration>,
+    stats: bool,
+}
+
+impl<K: Hash + Eq + Clone, V> S3FifoBuilder<K, V> {
+    pub fn new() -> Self {
+        Self {
+            capacity: 0,
+            small_ratio: 0.1,
+            max_freq: MAX_FREQ,
+            promotion_threshold: 2,
+            reset_on_promotion: false,
+            ghost_capacity: None,
+            ghost_fingerprints: false,
+            adaptive: None,
+            weigher: None,
+            listener: None,
+            clock: Box::new(SystemClock),
+            time_to_live: None,
+            time_to_idle: None,
+            stats: false,
+        }
+    }
+
+    /// Total weight of both queues together. Without a weigher, this is the number of entries.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Fraction of the capacity given to the small queue, at least 0 and less than 1.
+    pub fn small_ratio(mut self, ratio: f64) -> Self {
+        self.small_ratio = ratio;
+        self
+    }
+
+    /// Highest access frequency an entry can reach, which is how many times it can go back round
+    /// the main queue without being read again. Defaults to 3, and can be at most 127, which
+    /// leaves room for concurrent reads overshooting it before it's clamped.
+    pub fn max_freq(mut self, max_freq: u8) -> Self {
+        self.max_freq = max_freq;
+        self
+    }
+
+    /// Access frequency an entry needs by the time it leaves the small queue to be moved to the
+    /// main queue, rather than evicted. Defaults to 2, and can't be more than `max_freq`.
+    pub fn promotion_threshold(mut self, threshold: u8) -> Self {
+        self.promotion_threshold = threshold;
+        self
+    }
+
+    /// Whether entries start over with a frequency of 0 when promoted to the main queue, so they
+    /// must be read again there to survive its eviction. Off by default.
+    pub fn reset_on_promotion(mut self, reset: bool) -> Self {
+        self.reset_on_promotion = reset;
+        self
+    }
+
+    /// Total weight of the keys the ghost queue remembers. Defaults to the main queue's size.
+    pub fn ghost_capacity(mut self, capacity: usize) -> Self {
+        self.ghost_capacity = Some(capacity);
+        self
+    }
+
+    /// Whether the ghost queue remembers keys by a 64-bit hash, rather than a clone of the key.
+    /// That saves memory when keys are large, at the cost of the rare unrelated key being
+    /// mistaken for a ghost; see [`S3Fifo::ghost_false_positive_rate`]. Off by default.
+    pub fn ghost_fingerprints(mut self, fingerprints: bool) -> Self {
+        self.ghost_fingerprints = fingerprints;
+        self
+    }
+
+    /// Let the split between the queues adapt to the workload, keeping the small queue's share
+    /// of the capacity between `min` and `max`. See [`S3Fifo::set_adaptive`].
+    pub fn adaptive(mut self, min: f64, max: f64) -> Self {
+        self.adaptive = Some((min, max));
+        self
+    }
+
+    /// See [`S3Fifo::with_weigher`].
+    pub fn weigher(mut self, weigher: impl Weigher<K, V> + Send + Sync + 'static) -> Self {
+        self.weigher = Some(Box::new(weigher));
+        self
+    }
+
+    /// See [`S3Fifo::set_eviction_listener`].
+    pub fn eviction_listener(
+        mut self,
+        listener: impl EvictionListener<K, V> + Send + Sync + 'static,
+    ) -> Self {
+        self.listener = Some(Box::new(listener));
+        self
+    }
+
+    /// See [`S3Fifo::set_clock`].
+    pub fn clock(mut self, clock: impl Clock + Send + Sync + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// See [`S3Fifo::set_time_to_live`].
+    pub fn time_to_live(mut self, ttl: Duration) -> Self {
+        self.time_to_live = Some(ttl);
+        self
+    }
+
+    /// See [`S3Fifo::set_time_to_idle`].
+    pub fn time_to_idle(mut self, tti: Duration) -> Self {
+        self.time_to_idle = Some(tti);
+        self
+    }
+
+    /// See [`S3Fifo::enable_stats`].
+    pub fn stats(mut self, enabled: bool) -> Self {
+        self.stats = enabled;
+        self
+    }
+
+    pub fn build(self) -> Result<S3Fifo<K, V>, BuildError> {
+        if !(0.0 .. 1.0).contains(&self.small_ratio) {
+            return Err(BuildError::SmallRatio(self.small_ratio));
+        }
+        if let Some((min, max)) = self.adaptive {
+            if !(0.0 ..= max).contains(&min) || !(min .. 1.0).contains(&max) {
+                return Err(BuildError::AdaptiveBounds(min, max));
+            }
+        }
+        if self.max_freq == 0 || self.max_freq > 127 {
+            return Err(BuildError::MaxFreq(self.max_freq));
+        }
+        if self.promotion_threshold > self.max_freq {
+            return Err(BuildError::PromotionThreshold {
+                threshold: self.promotion_threshold,
+                max_freq: self.max_freq,
+            });
+        }
+        let small = (self.capacity as f64 * self.small_ratio).round() as usize;
+        let main = self.capacity - small;
+        Ok(self.assemble(small, main))
+    }
+
+    /// Build a cache with the given queue sizes, bypassing validation.
+    pub(crate) fn assemble(self, small: usize, main: usize) -> S3Fifo<K, V> {
+        let mut cache = S3Fifo {
+            slots: vec![],
+            free: vec![],
+            index: HashMap::new(),
+            small: List::new(),
+            main: List::new(),
+            ghost: GhostQueue::new(self.ghost_capacity.unwrap_or(main), self.ghost_fingerprints),
+            small_size: small,
+            main_size: main,
+            max_freq: self.max_freq,
+            promotion_threshold: self.promotion_threshold,
+            reset_on_promotion: self.reset_on_promotion,
+            adaptive: None,
+            weigher: self.weigher.unwrap_or_else(|| Box::new(UnitWeigher)),
+            listener: self.listener,
+            stats: None,
+            clock: self.clock,
+            time_to_live: self.time_to_live,
+            time_to_idle: self.time_to_idle,
+            expirations: BTreeSet::new(),
+            kept: None,
+        };
+        if self.stats {
+            cache.enable_stats();
+        }
+        if let Some((min, max)) = self.adaptive {
+            let total = small + main;
+            let share = |ratio: f64| (total as f64 * ratio).round() as usize;
+            cache.set_adaptive(Some(share(min) ..= share(max)));
+        }
+        cache
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> Default for S3FifoBuilder<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A combination of settings which `S3FifoBuilder` can't build a cache from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum BuildError {
+    /// The small queue ratio isn't at least 0 and less than 1.
+    SmallRatio(f64),
+    /// The adaptive bounds for the small queue ratio aren't ordered, or not in [0, 1).
+    AdaptiveBounds(f64, f64),
+    /// The max frequency isn't between 1 and 127.
+    MaxFreq(u8),
+    /// The promotion threshold is higher than the max frequency, so nothing would be promoted.
+    PromotionThreshold { threshold: u8, max_freq: u8 },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::SmallRatio(ratio) => {
+                write!(f, "small queue ratio {ratio} is not in [0, 1)")
+            }
+            BuildError::AdaptiveBounds(min, max) => {
+                write!(f, "adaptive small queue ratio bounds {min} and {max} are not valid")
+            }
+            BuildError::MaxFreq(max) => write!(f, "max frequency {max} is not in [1, 127]"),
+            BuildError::PromotionThreshold { threshold, max_freq } => write!(
+                f,
+                "promotion threshold {threshold} is higher than the max frequency {max_freq}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Queue;
+    use std::sync::atomic::Ordering::SeqCst;
+
+    fn freq(cache: &S3Fifo<u32, u32>, key: u32) -> u8 {
+        cache.slot(cache.index[&key]).freq.load(SeqCst)
+    }
+
+    #[test]
+    fn validation() {
+        let cache = S3Fifo::<u32, u32>::builder().capacity(100).build().unwrap();
+        assert_eq!((cache.small_size, cache.main_size), (10, 90));
+        let cache = S3Fifo::<u32, u32>::builder().capacity(5).small_ratio(0.0).build().unwrap();
+        assert_eq!((cache.small_size, cache.main_size), (0, 5));
+
+        let err = |b: S3FifoBuilder<u32, u32>| b.capacity(10).build().err().unwrap();
+        assert_eq!(err(S3Fifo::builder().small_ratio(1.0)), BuildError::SmallRatio(1.0));
+        assert!(matches!(err(S3Fifo::builder().small_ratio(f64::NAN)), BuildError::SmallRatio(_)));
+        assert_eq!(err(S3Fifo::builder().max_freq(0)), BuildError::MaxFreq(0));
+        assert_eq!(err(S3Fifo::builder().max_freq(200)), BuildError::MaxFreq(200));
+        assert_eq!(
+            err(S3Fifo::builder().promotion_threshold(4)).to_string(),
+            "promotion threshold 4 is higher than the max frequency 3"
+        );
+    }
+
+    #[test]
+    fn policy_knobs() {
+        let mut cache = S3Fifo::builder()
+            .capacity(10)
+            .max_freq(5)
+            .promotion_threshold(1)
+            .build()
+            .unwrap();
+        cache.insert(1, 1);
+        for _ in 0 .. 10 {
+            cache.read(&1);
+        }
+        assert_eq!(freq(&cache, 1), 5);
+        cache.insert(2, 2);
+        cache.read(&2);
+        cache.insert(3, 3);
+        assert_eq!(cache.queue_keys(Queue::Main), vec![&2, &1]);
+        assert_eq!(freq(&cache, 2), 1);
+
+        let mut cache = S3Fifo::builder()
+            .capacity(10)
+            .reset_on_promotion(true)
+            .ghost_capacity(0)
+            .build()
+            .unwrap();
+        cache.insert(1, 1);
+        cache.read(&1);
+        cache.read(&1);
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        assert_eq!(cache.queue_keys(Queue::Main), vec![&1]);
+        assert_eq!(freq(&cache, 1), 0);
+        // 2 was evicted, but the ghost queue has no room to remember it.
+        assert_eq!(cache.ghost.len(), 0);
+        cache.insert(2, 2);
+        assert_eq!(cache.queue_keys(Queue::Small), vec![&2]);
+    }
+}
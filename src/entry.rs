@@ -0,0 +1,164 @@
+//! In-place manipulation of cache entries, in the style of `std::collections::hash_map::Entry`.
+
+use std::hash::Hash;
+use std::sync::atomic::Ordering::SeqCst;
+
+use crate::{Rejected, RejectionReason, RemovalCause, S3Fifo};
+
+/// A view into a single key of the cache, which may or may not be cached.
+///
+/// Obtained from [`S3Fifo::entry`].
+pub enum Entry<'a, K, V> {
+    Occupied(OccupiedEntry<'a, K, V>),
+    Vacant(VacantEntry<'a, K, V>),
+}
+
+/// A key which is cached.
+pub struct OccupiedEntry<'a, K, V> {
+    pub(crate) cache: &'a mut S3Fifo<K, V>,
+    pub(crate) idx: usize,
+}
+
+/// A key which is not cached. Inserting it goes through the ghost queue check like
+/// [`S3Fifo::insert`] does, so it may evict other entries.
+///
+/// Inserting a value which doesn't fit, because it's too heavy for the cache or pinned entries
+/// leave no room for it, panics, and so do the `or_insert` family of methods on [`Entry`] and
+/// [`S3Fifo::get_or_insert_with`]. Use `try_insert`, or [`S3Fifo::try_get_or_insert_with`],
+/// to handle that case.
+pub struct VacantEntry<'a, K, V> {
+    pub(crate) cache: &'a mut S3Fifo<K, V>,
+    pub(crate) key: K,
+}
+
+impl<'a, K: Hash + Eq + Clone, V> Entry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(e) => e.key(),
+            Entry::Vacant(e) => e.key(),
+        }
+    }
+
+    pub fn or_insert(self, value: V) -> &'a mut V {
+        self.or_insert_with(|| value)
+    }
+
+    pub fn or_insert_with(self, f: impl FnOnce() -> V) -> &'a mut V {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(f()),
+        }
+    }
+
+    pub fn or_default(self) -> &'a mut V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    pub fn and_modify(mut self, f: impl FnOnce(&mut V)) -> Self {
+        if let Entry::Occupied(e) = &mut self {
+            f(e.get_mut());
+        }
+        self
+    }
+}
+
+impl<'a, K: Hash + Eq + Clone, V> OccupiedEntry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        &self.cache.slot(self.idx).key
+    }
+
+    pub fn get(&self) -> &V {
+        &self.cache.slot(self.idx).value
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.cache.slot_mut(self.idx).value
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        &mut self.cache.slot_mut(self.idx).value
+    }
+
+    /// Replace the value, keeping the entry's access frequency, and its place in its queue
+    /// unless it's now too heavy for the small one. Like [`S3Fifo::insert`], a heavier value
+    /// evicts other entries to make room.
+    ///
+    /// # Panics
+    ///
+    /// If the value is too heavy to fit in the cache. Use `try_insert` to handle that case.
+    pub fn insert(&mut self, value: V) -> V {
+        match self.try_insert(value) {
+            Ok(old) => old,
+            Err(rejected) => panic!("{rejected}"),
+        }
+    }
+
+    /// Replace the value, unless it is too heavy to fit in the cache, in which case the entry is
+    /// left unchanged.
+    pub fn try_insert(&mut self, value: V) -> Result<V, Rejected<K, V>> {
+        let weight = self.cache.weigher.weigh(self.key(), &value);
+        if weight > self.cache.main_size {
+            let reason = RejectionReason::TooHeavy;
+            return Err(Rejected { key: self.key().clone(), value, weight, reason });
+        }
+        let expires = self.cache.deadline(None);
+        Ok(self.cache.replace(self.idx, value, weight, false, expires))
+    }
+
+    /// Access frequency of the entry, from 0 up to the cache's max frequency.
+    pub fn freq(&self) -> u8 {
+        self.cache.slot(self.idx).freq.load(SeqCst)
+    }
+
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    pub fn remove_entry(self) -> (K, V) {
+        let cache = self.cache;
+        cache.unlink(self.idx);
+        let entry = cache.release(self.idx);
+        cache.index.remove(&entry.key);
+        cache.notify(&entry.key, &entry.value, RemovalCause::Removed);
+        (entry.key, entry.value)
+    }
+}
+
+impl<'a, K: Hash + Eq + Clone, V> VacantEntry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Insert a value for the key.
+    ///
+    /// # Panics
+    ///
+    /// If the value is too heavy to fit in the cache, or there's no room for it because of
+    /// pinned entries. Use `try_insert` to handle that case.
+    pub fn insert(self, value: V) -> &'a mut V {
+        match self.try_insert(value) {
+            Ok(value) => value,
+            Err(rejected) => panic!("{rejected}"),
+        }
+    }
+
+    /// Insert a value for the key, unless it is too heavy to fit in the cache, or pinned entries
+    /// are in the way.
+    pub fn try_insert(self, value: V) -> Result<&'a mut V, Rejected<K, V>> {
+        let weight = self.cache.weigher.weigh(&self.key, &value);
+        if weight > self.cache.main_size {
+            let reason = RejectionReason::TooHeavy;
+            return Err(Rejected { key: self.key, value, weight, reason });
+        }
+        let expires = self.cache.deadline(None);
+        let idx = self.cache.insert_new(self.key, value, weight, expires)?;
+        Ok(&mut self.cache.slot_mut(idx).value)
+    }
+}
@@ -0,0 +1,176 @@
+//! Coalescing concurrent loads of the same key, so that only one of them does the work.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll, Waker};
+
+/// A load's error, shared by everyone who waited for it. The type is erased, since each caller
+/// brings their own loader; a caller expecting another type just loads again.
+pub(crate) type SharedError = Arc<dyn Any + Send + Sync>;
+
+/// How a load ended.
+#[derive(Clone)]
+pub(crate) enum Outcome<V> {
+    Loaded(V),
+    Failed(SharedError),
+    /// The loader panicked, or its future was dropped, so there's no value, and waiters should
+    /// try again.
+    Abandoned,
+}
+
+/// A load in progress, which other callers can wait for, blocking a thread or as a future.
+pub(crate) struct Flight<V> {
+    state: Mutex<State<V>>,
+    landed: Condvar,
+}
+
+struct State<V> {
+    outcome: Option<Outcome<V>>,
+    // Tasks waiting for the outcome.
+    #[cfg(feature = "async")]
+    wakers: Vec<Waker>,
+}
+
+impl<V: Clone> Flight<V> {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                outcome: None,
+                #[cfg(feature = "async")]
+                wakers: Vec::new(),
+            }),
+            landed: Condvar::new(),
+        }
+    }
+
+    /// Block until the load ends, and return how.
+    pub fn wait(&self) -> Outcome<V> {
+        let mut state = lock(&self.state);
+        loop {
+            if let Some(outcome) = &state.outcome {
+                return outcome.clone();
+            }
+            state = self.landed.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    fn land(&self, outcome: Outcome<V>) {
+        let mut state = lock(&self.state);
+        state.outcome = Some(outcome);
+        self.landed.notify_all();
+        #[cfg(feature = "async")]
+        for waker in state.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// A future of how a flight ends, for tasks which mustn't block their thread.
+#[cfg(feature = "async")]
+pub(crate) struct Landing<V>(pub Arc<Flight<V>>);
+
+#[cfg(feature = "async")]
+impl<V: Clone> Future for Landing<V> {
+    type Output = Outcome<V>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Outcome<V>> {
+        let mut state = lock(&self.0.state);
+        if let Some(outcome) = &state.outcome {
+            return Poll::Ready(outcome.clone());
+        }
+        if !state.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            state.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+/// The loads in progress, by key.
+pub(crate) struct Flights<K, V> {
+    flights: Mutex<HashMap<K, Arc<Flight<V>>>>,
+}
+
+/// What a caller looking for a key should do.
+pub(crate) enum Role<'a, K: Hash + Eq, V: Clone> {
+    /// Nothing, since the key is cached.
+    Cached(V),
+    /// Load the key, and hand the outcome to the waiters.
+    Leader(Leader<'a, K, V>),
+    /// Wait for the leader.
+    Follower(Arc<Flight<V>>),
+}
+
+impl<K, V> Flights<K, V> {
+    pub fn new() -> Self {
+        Self {
+            flights: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Flights<K, V> {
+    /// Join the load of a key in progress, or else start one, unless `cached` finds the key,
+    /// which it might if another load just ended.
+    ///
+    /// A leader puts the value in the cache before it stops being in flight, and `cached` is
+    /// checked while no flight can stop, so a key is never loaded twice because a caller just
+    /// missed the end of a flight.
+    pub fn join(&self, key: &K, cached: impl FnOnce() -> Option<V>) -> Role<'_, K, V> {
+        let mut flights = lock(&self.flights);
+        if let Some(flight) = flights.get(key) {
+            return Role::Follower(Arc::clone(flight));
+        }
+        if let Some(value) = cached() {
+            return Role::Cached(value);
+        }
+        let flight = Arc::new(Flight::new());
+        flights.insert(key.clone(), Arc::clone(&flight));
+        Role::Leader(Leader {
+            flights: self,
+            key: key.clone(),
+            flight,
+            outcome: None,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        lock(&self.flights).len()
+    }
+}
+
+/// The caller doing the load. If it's dropped without landing, because the loader panicked or
+/// its future was dropped, the waiters are told the flight was abandoned.
+pub(crate) struct Leader<'a, K: Hash + Eq, V: Clone> {
+    flights: &'a Flights<K, V>,
+    key: K,
+    flight: Arc<Flight<V>>,
+    outcome: Option<Outcome<V>>,
+}
+
+impl<K: Hash + Eq, V: Clone> Leader<'_, K, V> {
+    /// End the flight. Anything loaded must be cached first.
+    pub fn land(mut self, outcome: Outcome<V>) {
+        self.outcome = Some(outcome);
+    }
+}
+
+impl<K: Hash + Eq, V: Clone> Drop for Leader<'_, K, V> {
+    fn drop(&mut self) {
+        lock(&self.flights.flights).remove(&self.key);
+        self.flight.land(self.outcome.take().unwrap_or(Outcome::Abandoned));
+    }
+}
+
+// Nothing panics while these locks are held, but a panicking loader mustn't make every later
+// load of the same key fail too, so poisoning is ignored.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,211 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hash};
+
+/// The ghost queue of a cache, which holds either clones of the evicted keys, or just 64-bit
+/// fingerprints of them when keys are large.
+///
+/// With fingerprints, a key which was never evicted can be mistaken for one that was, if its
+/// fingerprint matches one of the remembered ones. All that does is send the key to the main
+/// queue instead of the small one.
+pub(crate) enum GhostQueue<K> {
+    Keys(Ghost<K>),
+    Fingerprints(Ghost<u64>, RandomState),
+}
+
+impl<K: Hash + Eq + Clone> GhostQueue<K> {
+    pub fn new(capacity: usize, fingerprints: bool) -> Self {
+        if fingerprints {
+            GhostQueue::Fingerprints(Ghost::new(capacity), RandomState::new())
+        } else {
+            GhostQueue::Keys(Ghost::new(capacity))
+        }
+    }
+
+    pub fn insert(&mut self, key: K, weight: usize) {
+        match self {
+            GhostQueue::Keys(ghost) => ghost.insert(key, weight),
+            GhostQueue::Fingerprints(ghost, hasher) => ghost.insert(hasher.hash_one(key), weight),
+        }
+    }
+
+    /// Remove a key, returning whether it was there.
+    pub fn remove<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self {
+            GhostQueue::Keys(ghost) => ghost.remove(key),
+            GhostQueue::Fingerprints(ghost, hasher) => ghost.remove(&hasher.hash_one(key)),
+        }
+    }
+
+    /// Probability that a key which isn't in the ghost queue is taken to be in it, given how
+    /// many fingerprints it holds. Always 0 when it holds keys.
+    pub fn false_positive_rate(&self) -> f64 {
+        match self {
+            GhostQueue::Keys(_) => 0.0,
+            GhostQueue::Fingerprints(ghost, _) => ghost.map.len() as f64 / 2f64.powi(64),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        match self {
+            GhostQueue::Keys(ghost) => ghost.capacity,
+            GhostQueue::Fingerprints(ghost, _) => ghost.capacity,
+        }
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        match self {
+            GhostQueue::Keys(ghost) => ghost.set_capacity(capacity),
+            GhostQueue::Fingerprints(ghost, _) => ghost.set_capacity(capacity),
+        }
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        match self {
+            GhostQueue::Keys(ghost) => ghost.reserve(additional),
+            GhostQueue::Fingerprints(ghost, _) => ghost.reserve(additional),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        match self {
+            GhostQueue::Keys(ghost) => ghost.clear(),
+            GhostQueue::Fingerprints(ghost, _) => ghost.clear(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        match self {
+            GhostQueue::Keys(ghost) => ghost.len(),
+            GhostQueue::Fingerprints(ghost, _) => ghost.len(),
+        }
+    }
+}
+
+/// The ghost queue remembers keys recently evicted from the small queue, without their values.
+///
+/// Its capacity is a total weight, like the cache's queues, so that it remembers roughly as many
+/// keys as the main queue can hold.
+///
+/// Membership is answered from a hash map, while a FIFO of (key, sequence number) pairs provides
+/// the aging order. Removing a key only drops it from the map; its stale FIFO slot is recognized
+/// by the sequence number not matching anymore, and is discarded when it reaches the tail.
+pub(crate) struct Ghost<K> {
+    map: HashMap<K, u64>,
+    fifo: VecDeque<(K, u64, usize)>,
+    capacity: usize,
+    // Weight of everything in the FIFO, including stale entries.
+    weight: usize,
+    seq: u64,
+}
+
+impl<K: Hash + Eq + Clone> Ghost<K> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            map: HashMap::new(),
+            fifo: VecDeque::new(),
+            capacity,
+            weight: 0,
+            seq: 0,
+        }
+    }
+
+    /// Make room for `additional` more keys without reallocating.
+    pub fn reserve(&mut self, additional: usize) {
+        self.map.reserve(additional);
+        self.fifo.reserve(additional);
+    }
+
+    /// Change the capacity, forgetting the oldest keys if it shrinks.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.weight > capacity {
+            self.pop_back();
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn insert(&mut self, key: K, weight: usize) {
+        if weight > self.capacity {
+            return;
+        }
+        while !self.fifo.is_empty() && self.weight + weight > self.capacity {
+            self.pop_back();
+        }
+        let seq = self.seq;
+        self.seq += 1;
+        self.weight += weight;
+        self.fifo.push_front((key.clone(), seq, weight));
+        self.map.insert(key, seq);
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.remove(key).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.fifo.clear();
+        self.weight = 0;
+    }
+
+    fn pop_back(&mut self) {
+        if let Some((key, seq, weight)) = self.fifo.pop_back() {
+            self.weight -= weight;
+            if self.map.get(&key) == Some(&seq) {
+                self.map.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Queue, S3Fifo};
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn fingerprints_match_keys() {
+        let build = |fingerprints| {
+            S3Fifo::<String, u32>::builder()
+                .capacity(50)
+                .ghost_fingerprints(fingerprints)
+                .build()
+                .unwrap()
+        };
+        let mut keys = build(false);
+        let mut fingerprints = build(true);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        for _ in 0 .. 10_000 {
+            let k: u32 = rng.gen_range(0..200);
+            let key = format!("some rather long key number {k}");
+            if keys.read(&key).is_none() {
+                keys.insert(key.clone(), k);
+            }
+            if fingerprints.read(&key).is_none() {
+                fingerprints.insert(key, k);
+            }
+        }
+        for queue in [Queue::Small, Queue::Main] {
+            assert_eq!(keys.queue_keys(queue), fingerprints.queue_keys(queue));
+        }
+        assert_eq!(keys.ghost.len(), fingerprints.ghost.len());
+        assert_eq!(keys.ghost_false_positive_rate(), 0.0);
+        let rate = fingerprints.ghost_false_positive_rate();
+        assert!(rate > 0.0 && rate < 1e-15, "{rate}");
+    }
+}
@@ -0,0 +1,423 @@
+//! Iterators over the entries of an `S3Fifo`.
+//!
+//! They all visit the small queue from head to tail, then the main queue from head to tail, so
+//! each queue is seen from its newest entry to the one closest to eviction.
+
+use std::hash::Hash;
+use std::iter::FusedIterator;
+use std::marker::PhantomData;
+use std::sync::atomic::Ordering::SeqCst;
+
+use crate::{Entry, List, Queue, RemovalCause, S3Fifo, NIL};
+
+/// Position in a walk over both queues, small then main.
+#[derive(Clone)]
+struct Cursor {
+    idx: usize,
+    // Head of the main queue, until the walk gets to it.
+    main: usize,
+}
+
+impl Cursor {
+    fn new(small: &List, main: &List) -> Self {
+        Self {
+            idx: small.head,
+            main: main.head,
+        }
+    }
+
+    /// The slot the walk is at, or `None` at the end.
+    fn current(&mut self) -> Option<usize> {
+        if self.idx == NIL {
+            self.idx = std::mem::replace(&mut self.main, NIL);
+        }
+        (self.idx != NIL).then_some(self.idx)
+    }
+}
+
+/// An entry as seen by `S3Fifo::iter_info`.
+#[derive(Debug, Clone, Copy)]
+pub struct EntryInfo<'a, K, V> {
+    pub key: &'a K,
+    pub value: &'a V,
+    /// The queue the entry is in.
+    pub queue: Queue,
+    /// How many times the entry was accessed, up to the cache's max frequency.
+    pub freq: u8,
+}
+
+/// Iterator over the entries of a cache with their queue and frequency, from
+/// `S3Fifo::iter_info`.
+#[derive(Clone)]
+pub struct IterInfo<'a, K, V> {
+    cache: &'a S3Fifo<K, V>,
+    cursor: Cursor,
+}
+
+impl<'a, K: Hash + Eq + Clone, V> Iterator for IterInfo<'a, K, V> {
+    type Item = EntryInfo<'a, K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = self.cache.slot(self.cursor.current()?);
+            self.cursor.idx = entry.next;
+            if !self.cache.is_expired(entry) {
+                return Some(EntryInfo {
+                    key: &entry.key,
+                    value: &entry.value,
+                    queue: entry.queue,
+                    freq: entry.freq.load(SeqCst),
+                });
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.cache.len()))
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> FusedIterator for IterInfo<'_, K, V> {}
+
+/// Iterator over the entries of a cache, from `S3Fifo::iter`.
+#[derive(Clone)]
+pub struct Iter<'a, K, V> {
+    inner: IterInfo<'a, K, V>,
+}
+
+impl<'a, K: Hash + Eq + Clone, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|info| (info.key, info.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> FusedIterator for Iter<'_, K, V> {}
+
+/// Iterator over the keys of a cache, from `S3Fifo::keys`.
+#[derive(Clone)]
+pub struct Keys<'a, K, V> {
+    inner: Iter<'a, K, V>,
+}
+
+impl<'a, K: Hash + Eq + Clone, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(key, _)| key)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> FusedIterator for Keys<'_, K, V> {}
+
+/// Iterator over the values of a cache, from `S3Fifo::values`.
+#[derive(Clone)]
+pub struct Values<'a, K, V> {
+    inner: Iter<'a, K, V>,
+}
+
+impl<'a, K: Hash + Eq + Clone, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(_, value)| value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> FusedIterator for Values<'_, K, V> {}
+
+/// Iterator over the entries of a cache with mutable access to the values, from
+/// `S3Fifo::iter_mut`.
+pub struct IterMut<'a, K, V> {
+    // The cache's slots, borrowed mutably for `'a`. A raw pointer rather than a slice, since
+    // the entries handed out borrow from it for as long as the slice would.
+    slots: *mut Option<Entry<K, V>>,
+    cursor: Cursor,
+    remaining: usize,
+    marker: PhantomData<&'a mut [Option<Entry<K, V>>]>,
+}
+
+// Safety: `IterMut` is a mutable borrow of the slots, so it can be shared or sent whenever one
+// could be.
+unsafe impl<K: Send, V: Send> Send for IterMut<'_, K, V> {}
+unsafe impl<K: Sync, V: Sync> Sync for IterMut<'_, K, V> {}
+
+impl<'a, K, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let idx = self.cursor.current()?;
+        // Safety: the queues hold each live slot at most once, and the slab can't change while
+        // it's borrowed, so `idx` is in bounds and no entry is handed out twice.
+        let entry = unsafe { &mut *self.slots.add(idx) };
+        let entry = entry.as_mut().expect("dangling slot index");
+        self.cursor.idx = entry.next;
+        self.remaining -= 1;
+        Some((&entry.key, &mut entry.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V> ExactSizeIterator for IterMut<'_, K, V> {}
+impl<K, V> FusedIterator for IterMut<'_, K, V> {}
+
+/// The entries taken out of a cache, in queue order, without the queues.
+struct Taken<K, V> {
+    slots: Vec<Option<Entry<K, V>>>,
+    cursor: Cursor,
+    remaining: usize,
+}
+
+impl<K, V> Taken<K, V> {
+    fn next(&mut self) -> Option<Entry<K, V>> {
+        let idx = self.cursor.current()?;
+        let entry = self.slots[idx].take().expect("entry visited twice");
+        self.cursor.idx = entry.next;
+        self.remaining -= 1;
+        Some(entry)
+    }
+}
+
+/// Iterator removing every entry from a cache, from `S3Fifo::drain`.
+///
+/// The entries not yet iterated over when it is dropped are removed too.
+pub struct Drain<'a, K: Hash + Eq + Clone, V> {
+    // Already emptied; only kept for its eviction listener.
+    cache: &'a mut S3Fifo<K, V>,
+    taken: Taken<K, V>,
+}
+
+impl<K: Hash + Eq + Clone, V> Iterator for Drain<'_, K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.taken.next()?;
+        self.cache.notify(&entry.key, &entry.value, RemovalCause::Removed);
+        Some((entry.key, entry.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.taken.remaining, Some(self.taken.remaining))
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> ExactSizeIterator for Drain<'_, K, V> {}
+impl<K: Hash + Eq + Clone, V> FusedIterator for Drain<'_, K, V> {}
+
+impl<K: Hash + Eq + Clone, V> Drop for Drain<'_, K, V> {
+    fn drop(&mut self) {
+        self.for_each(drop);
+    }
+}
+
+/// Iterator moving the entries out of a cache, from its `IntoIterator` implementation.
+pub struct IntoIter<K, V> {
+    taken: Taken<K, V>,
+}
+
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.taken.next().map(|entry| (entry.key, entry.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.taken.remaining, Some(self.taken.remaining))
+    }
+}
+
+impl<K, V> ExactSizeIterator for IntoIter<K, V> {}
+impl<K, V> FusedIterator for IntoIter<K, V> {}
+
+impl<K: Hash + Eq + Clone, V> S3Fifo<K, V> {
+    /// Iterate over the cached entries, in queue order: the small queue from head to tail, then
+    /// the main queue. This doesn't count as accessing them, and skips expired entries.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            inner: self.iter_info(),
+        }
+    }
+
+    /// Iterate over the cached keys, in the same order as `iter`.
+    pub fn keys(&self) -> Keys<'_, K, V> {
+        Keys { inner: self.iter() }
+    }
+
+    /// Iterate over the cached values, in the same order as `iter`.
+    pub fn values(&self) -> Values<'_, K, V> {
+        Values { inner: self.iter() }
+    }
+
+    /// Like `iter`, but also giving the queue each entry is in and its access frequency.
+    pub fn iter_info(&self) -> IterInfo<'_, K, V> {
+        IterInfo {
+            cache: self,
+            cursor: Cursor::new(&self.small, &self.main),
+        }
+    }
+
+    /// Like `iter`, but with mutable access to the values. Expired entries are reclaimed first.
+    ///
+    /// As with `get_mut`, changed values are not re-weighed.
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        self.remove_expired();
+        let cursor = Cursor::new(&self.small, &self.main);
+        IterMut {
+            remaining: self.len(),
+            slots: self.slots.as_mut_ptr(),
+            cursor,
+            marker: PhantomData,
+        }
+    }
+
+    /// Remove every entry, returning them in the same order as `iter`. Like `invalidate_all`,
+    /// this keeps the ghost queue. Expired entries are reclaimed first, rather than returned.
+    pub fn drain(&mut self) -> Drain<'_, K, V> {
+        self.remove_expired();
+        let taken = self.take_all();
+        Drain { cache: self, taken }
+    }
+
+    fn take_all(&mut self) -> Taken<K, V> {
+        let taken = Taken {
+            slots: std::mem::take(&mut self.slots),
+            cursor: Cursor::new(&self.small, &self.main),
+            remaining: self.len(),
+        };
+        self.free.clear();
+        self.index.clear();
+        self.expirations.clear();
+        self.small = List::new();
+        self.main = List::new();
+        taken
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> IntoIterator for S3Fifo<K, V> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    /// Move the entries out of the cache, in the same order as `iter`, leaving out expired ones.
+    fn into_iter(mut self) -> IntoIter<K, V> {
+        self.remove_expired();
+        IntoIter {
+            taken: self.take_all(),
+        }
+    }
+}
+
+impl<'a, K: Hash + Eq + Clone, V> IntoIterator for &'a S3Fifo<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<'a, K: Hash + Eq + Clone, V> IntoIterator for &'a mut S3Fifo<K, V> {
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V>;
+
+    fn into_iter(self) -> IterMut<'a, K, V> {
+        self.iter_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn cache() -> S3Fifo<u32, u32> {
+        let mut q = S3Fifo::new(2, 4);
+        q.insert(1, 10);
+        q.insert(2, 20);
+        q.read(&1);
+        q.read(&1);
+        for k in [3, 4, 2] {
+            q.insert(k, k * 10);
+        }
+        q
+    }
+
+    #[test]
+    fn order() {
+        let mut q = cache();
+        assert_eq!(q.keys().copied().collect::<Vec<_>>(), [4, 3, 2, 1]);
+        assert_eq!(q.values().copied().collect::<Vec<_>>(), [40, 30, 20, 10]);
+        let info: Vec<_> = q.iter_info().map(|e| (*e.key, e.queue, e.freq)).collect();
+        assert_eq!(
+            info,
+            [(4, Queue::Small, 0), (3, Queue::Small, 0), (2, Queue::Main, 0), (1, Queue::Main, 2)]
+        );
+        // Iterating didn't count as accesses.
+        assert_eq!(q.iter_info().map(|e| e.freq).sum::<u8>(), 2);
+
+        for (k, v) in &mut q {
+            *v += k;
+        }
+        assert_eq!((&q).into_iter().map(|(_, v)| *v).collect::<Vec<_>>(), [44, 33, 22, 11]);
+        assert_eq!(q.iter_mut().len(), 4);
+        assert_eq!(q.into_iter().collect::<Vec<_>>(), [(4, 44), (3, 33), (2, 22), (1, 11)]);
+    }
+
+    #[test]
+    fn drain() {
+        let removed = Arc::new(Mutex::new(vec![]));
+        let mut q = cache();
+        // Evicts 3 to the ghost queue.
+        q.insert(5, 50);
+        let log = Arc::clone(&removed);
+        q.set_eviction_listener(move |k: &u32, _: &u32, cause| {
+            log.lock().unwrap().push((*k, cause));
+        });
+
+        let mut drain = q.drain();
+        assert_eq!(drain.len(), 4);
+        assert_eq!(drain.next(), Some((5, 50)));
+        drop(drain);
+        assert!(q.is_empty());
+        assert_eq!(q.iter().count(), 0);
+        q.check_invariants();
+        let removed = removed.lock().unwrap().clone();
+        assert_eq!(removed, [5, 4, 2, 1].map(|k| (k, RemovalCause::Removed)));
+
+        // The ghost queue is kept.
+        q.insert(3, 30);
+        assert_eq!(q.queue_keys(Queue::Main), vec![&3]);
+    }
+
+    #[test]
+    fn skips_expired() {
+        use crate::ManualClock;
+        use std::time::Duration;
+
+        let clock = Arc::new(ManualClock::new());
+        let mut q = S3Fifo::new(5, 5);
+        q.set_clock(Arc::clone(&clock));
+        q.insert_with_ttl(1, 1, Duration::from_secs(1));
+        q.insert(2, 2);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(q.keys().collect::<Vec<_>>(), [&2]);
+        assert_eq!(q.iter_mut().len(), 1);
+        assert_eq!(q.len(), 1);
+    }
+}
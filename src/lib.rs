@@ -1,107 +1,1088 @@
 //! Simple implementation of "S3-FIFO" from "FIFO Queues are ALL You Need for Cache Eviction" by
 //! Juncheng Yang, et al: https://jasony.me/publication/sosp23-s3fifo.pdf
 
-use std::collections::VecDeque;
-use std::sync::atomic::AtomicU8;
+use std::borrow::Borrow;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, AtomicU8};
 use std::sync::atomic::Ordering::SeqCst;
+use std::time::Duration;
 
-// The paper uses two bits to count accesses, for a max of 3. We use 8 bit atomics, but will limit
-// the count to the same value, to prevent wrap-arounds causing problems.
+mod adaptive;
+mod builder;
+mod clock;
+pub mod entry;
+mod flight;
+mod ghost;
+pub mod iter;
+mod listener;
+mod loading;
+mod lockfree;
+mod mrc;
+pub mod policy;
+mod sharded;
+mod stats;
+mod weigher;
+pub mod workload;
+mod writer;
+
+use adaptive::Adaptive;
+use clock::nanos;
+use ghost::GhostQueue;
+use stats::Counters;
+pub use builder::{BuildError, S3FifoBuilder};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use listener::{EvictionListener, RemovalCause};
+pub use loading::{CacheLoader, LoadingCache};
+pub use lockfree::LockFreeS3Fifo;
+pub use mrc::{MissRatioCurve, MrcPoint};
+pub use policy::CachePolicy;
+pub use sharded::ShardedS3Fifo;
+pub use stats::Stats;
+pub use weigher::{Rejected, RejectionReason, UnitWeigher, Weigher};
+pub use writer::{CacheWriter, FlushError, WriteMode, WritingCache};
+
+// The paper uses two bits to count accesses, for a max of 3. We use 8 bit atomics, but by default
+// limit the count to the same value. Some limit is needed anyway, to prevent wrap-arounds causing
+// problems.
 const MAX_FREQ: u8 = 3;
 
+// Sentinel slot index used for the ends of the queues.
+const NIL: usize = usize::MAX;
+
+// Deadline of entries which don't expire.
+const NEVER: u64 = u64::MAX;
+
+/// The queue an entry is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Queue {
+    /// Where new entries start out.
+    Small,
+    /// Where entries which were accessed while in the small queue go, along with keys found in
+    /// the ghost queue.
+    Main,
+}
+
 struct Entry<K, V> {
     key: K,
     value: V,
     freq: AtomicU8,
+    weight: usize,
+    queue: Queue,
+    // When the entry expires, and when it was last written or read, in nanoseconds on the
+    // cache's clock.
+    expires: u64,
+    accessed: AtomicU64,
+    // Number of times the entry was pinned and not yet unpinned. Pinned entries aren't evicted.
+    pins: usize,
+    prev: usize,
+    next: usize,
 }
 
 impl<K, V> Entry<K, V> {
-    pub fn new(key: K, value: V) -> Self {
+    pub fn new(key: K, value: V, weight: usize, queue: Queue, now: u64, expires: u64) -> Self {
         Self {
             key,
             value,
             freq: AtomicU8::new(0),
+            weight,
+            queue,
+            expires,
+            accessed: AtomicU64::new(now),
+            pins: 0,
+            prev: NIL,
+            next: NIL,
+        }
+    }
+
+    /// Count an access to the entry.
+    fn bump(&self, max: u8) {
+        bump(&self.freq, max);
+    }
+}
+
+/// Split a total capacity in the same proportions as the given small and main sizes, or the
+/// paper's 10% small queue if they are both 0.
+fn split_capacity(total: usize, small: usize, main: usize) -> (usize, usize) {
+    let small = match small + main {
+        0 => total / 10,
+        old => (total as u128 * small as u128 / old as u128) as usize,
+    };
+    (small, total - small)
+}
+
+fn bump(freq: &AtomicU8, max: u8) {
+    if freq.fetch_add(1, SeqCst) + 1 > max {
+        // Clamp it.
+        freq.store(max, SeqCst);
+    }
+}
+
+/// A FIFO queue threaded through the entry slots. New entries are pushed on the head, and
+/// eviction looks at the tail.
+#[derive(Debug, Clone, Copy)]
+struct List {
+    head: usize,
+    tail: usize,
+    len: usize,
+    weight: usize,
+    // Number of entries which are pinned.
+    pinned: usize,
+}
+
+impl List {
+    fn new() -> Self {
+        Self {
+            head: NIL,
+            tail: NIL,
+            len: 0,
+            weight: 0,
+            pinned: 0,
         }
     }
 }
 
-pub struct S3Fifo<K: PartialEq, V> {
-    small: VecDeque<Entry<K, V>>,
-    main: VecDeque<Entry<K, V>>,
-    ghost: VecDeque<K>,
+pub struct S3Fifo<K, V> {
+    // Entries of both queues live in this slab, and the queues are linked lists of slot indices,
+    // so that the index below can point at an entry no matter where it sits in its queue.
+    slots: Vec<Option<Entry<K, V>>>,
+    free: Vec<usize>,
+    index: HashMap<K, usize>,
+    small: List,
+    main: List,
+    ghost: GhostQueue<K>,
     small_size: usize,
     main_size: usize,
+    max_freq: u8,
+    // Frequency an entry needs to be promoted when it leaves the small queue.
+    promotion_threshold: u8,
+    reset_on_promotion: bool,
+    adaptive: Option<Adaptive>,
+    weigher: Box<dyn Weigher<K, V> + Send + Sync>,
+    listener: Option<Box<dyn EvictionListener<K, V> + Send + Sync>>,
+    stats: Option<Counters>,
+    clock: Box<dyn Clock + Send + Sync>,
+    time_to_live: Option<Duration>,
+    time_to_idle: Option<Duration>,
+    // Slots of the entries which have a deadline, in the order they expire.
+    expirations: BTreeSet<(u64, usize)>,
+    // Entries which were evicted or expired, kept for the owner to take rather than dropped,
+    // when it asked for them with `keep_evicted`.
+    kept: Option<Vec<(K, V)>>,
 }
 
-impl<K: PartialEq, V> S3Fifo<K, V> {
+impl<K: Hash + Eq + Clone, V> S3Fifo<K, V> {
+    /// Create a cache holding up to `small` entries in the small queue and `main` entries in the
+    /// main queue.
     pub fn new(small: usize, main: usize) -> Self {
-        Self {
-            small: VecDeque::with_capacity(small),
-            main: VecDeque::with_capacity(main),
-            ghost: VecDeque::with_capacity(main),
-            small_size: small,
-            main_size: main,
+        let mut cache = Self::with_weigher(small, main, UnitWeigher);
+        cache.slots.reserve(small + main);
+        cache.index.reserve(small + main);
+        cache.ghost.reserve(main);
+        cache
+    }
+
+    /// Create a cache where `small` and `main` are the total weight each queue can hold, as
+    /// measured by the given weigher.
+    ///
+    /// The ghost queue remembers evicted keys up to the same total weight as the main queue.
+    pub fn with_weigher(
+        small: usize,
+        main: usize,
+        weigher: impl Weigher<K, V> + Send + Sync + 'static,
+    ) -> Self {
+        S3FifoBuilder::new().weigher(weigher).assemble(small, main)
+    }
+
+    /// Configure a cache in more detail, starting from a total capacity.
+    pub fn builder() -> S3FifoBuilder<K, V> {
+        S3FifoBuilder::new()
+    }
+
+    /// Expire entries once this long has passed since they were inserted or their value was
+    /// replaced, unless they were inserted with their own time to live.
+    pub fn set_time_to_live(&mut self, ttl: Option<Duration>) {
+        self.time_to_live = ttl;
+    }
+
+    /// Expire entries once this long has passed without them being read or written.
+    pub fn set_time_to_idle(&mut self, tti: Option<Duration>) {
+        self.time_to_idle = tti;
+    }
+
+    /// Use another clock to decide when entries expire. This should be done before inserting
+    /// anything, as the deadlines of cached entries are not converted.
+    pub fn set_clock(&mut self, clock: impl Clock + Send + Sync + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Set a listener to be told about every entry leaving the cache.
+    pub fn set_eviction_listener(
+        &mut self,
+        listener: impl EvictionListener<K, V> + Send + Sync + 'static,
+    ) {
+        self.listener = Some(Box::new(listener));
+    }
+
+    /// Start counting hits, misses and queue movements, to be read with `stats`. Until this is
+    /// called, nothing is counted.
+    pub fn enable_stats(&mut self) {
+        self.stats.get_or_insert_with(Counters::default);
+    }
+
+    /// What the cache has been doing since statistics were enabled or last reset. The counters
+    /// are all zeros if they aren't enabled.
+    pub fn stats(&self) -> Stats {
+        let counters = self.stats.as_ref().map_or_else(Stats::default, Counters::snapshot);
+        Stats {
+            small_size: self.small_size,
+            main_size: self.main_size,
+            ..counters
         }
     }
 
-    pub fn insert(&mut self, key: K, value: V) {
-        // This could be implemented using lock-free queues to not require &mut self, but that is
-        // left as an exercise to the reader.
-        if self.ghost.contains(&key) {
-            if self.main.len() >= self.main_size {
-                self.evict_main();
-            }
-            self.main.push_front(Entry::new(key, value));
+    /// Let the split between the small and main queues adapt to the workload, keeping the small
+    /// queue's size within the given bounds, or stop adapting with `None`.
+    ///
+    /// Ghost hits are taken as a sign that the small queue is too small, and reinsertions in the
+    /// main queue as a sign that the main queue is earning its space. Whichever is more common,
+    /// relative to the evictions from its queue, over a number of insertions similar to the
+    /// number of cached entries moves 1% of the capacity to its queue. The current split can be
+    /// seen in `stats`.
+    pub fn set_adaptive(&mut self, small_bounds: Option<RangeInclusive<usize>>) {
+        self.adaptive = small_bounds.map(Adaptive::new);
+    }
+
+    /// Set all the statistics back to zero.
+    pub fn reset_stats(&self) {
+        if let Some(stats) = &self.stats {
+            stats.reset();
+        }
+    }
+
+    /// Insert a value, or replace the value of a key that is already cached.
+    ///
+    /// Replacing a value keeps the entry where it is, along with its access frequency, and
+    /// returns the previous value. A heavier value evicts other entries to make room, and moves
+    /// to the main queue if it's too heavy for the small one.
+    ///
+    /// An entry too heavy to fit in the main queue is not inserted; if the key was cached, the
+    /// now-stale previous value is removed and returned. Neither is a new entry when pinned
+    /// entries leave no room for it, and then `None` is returned just as for a successful insert,
+    /// so caches which pin entries should use `try_insert`, which reports both cases.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.upsert(key, value, false, None)
+            .unwrap_or_else(|rejected| self.remove(&rejected.key))
+    }
+
+    /// Like `insert`, but a replaced entry also has its access frequency reset, as if it had
+    /// never been read.
+    pub fn insert_reset(&mut self, key: K, value: V) -> Option<V> {
+        self.upsert(key, value, true, None)
+            .unwrap_or_else(|rejected| self.remove(&rejected.key))
+    }
+
+    /// Like `insert`, but the entry expires once `ttl` has passed, instead of after the cache's
+    /// default time to live.
+    pub fn insert_with_ttl(&mut self, key: K, value: V, ttl: Duration) -> Option<V> {
+        self.upsert(key, value, false, Some(ttl))
+            .unwrap_or_else(|rejected| self.remove(&rejected.key))
+    }
+
+    /// Like `insert`, but an entry which doesn't fit is handed back in an error, with the reason.
+    /// If it's too heavy to ever fit, the cache is left unchanged; if it's pinned entries in the
+    /// way, the unpinned ones may have been evicted trying to make room.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<Option<V>, Rejected<K, V>> {
+        self.upsert(key, value, false, None)
+    }
+
+    fn upsert(
+        &mut self,
+        key: K,
+        value: V,
+        reset_freq: bool,
+        ttl: Option<Duration>,
+    ) -> Result<Option<V>, Rejected<K, V>> {
+        let weight = self.weigher.weigh(&key, &value);
+        if weight > self.main_size {
+            return Err(Rejected { key, value, weight, reason: RejectionReason::TooHeavy });
+        }
+        self.expire_due();
+        self.expire_key(&key);
+        let expires = self.deadline(ttl);
+        if let Some(&idx) = self.index.get(&key) {
+            return Ok(Some(self.replace(idx, value, weight, reset_freq, expires)));
+        }
+        self.insert_new(key, value, weight, expires)?;
+        Ok(None)
+    }
+
+    /// Replace the value of a cached entry with one which fits in the main queue, making room
+    /// for it if it's heavier, and return the old value.
+    fn replace(
+        &mut self,
+        idx: usize,
+        value: V,
+        weight: usize,
+        reset_freq: bool,
+        expires: u64,
+    ) -> V {
+        let old = self.replace_value(idx, value, weight);
+        self.set_expiry(idx, expires);
+        let entry = self.slot(idx);
+        if reset_freq {
+            entry.freq.store(0, SeqCst);
+        }
+        let mut queue = entry.queue;
+        if queue == Queue::Small && weight > self.small_size {
+            // Like a new entry, one too heavy for the small queue at all goes to main.
+            self.unlink(idx);
+            self.link_front(Queue::Main, idx);
+            queue = Queue::Main;
+        }
+        // The new value may be heavier than the old one. The entry itself is pinned while
+        // making room, so that it's other entries which are evicted.
+        self.pin_slot(idx);
+        self.make_room(queue, 0, None);
+        self.unpin_slot(idx);
+        self.notify(&self.slot(idx).key, &old, RemovalCause::Replaced);
+        old
+    }
+
+    /// Insert a key which is known not to be cached yet, and return its slot index, unless
+    /// pinned entries leave no room for it.
+    fn insert_new(
+        &mut self,
+        key: K,
+        value: V,
+        weight: usize,
+        expires: u64,
+    ) -> Result<usize, Rejected<K, V>> {
+        // This could be implemented using lock-free queues to not require &mut self; see
+        // LockFreeS3Fifo for that.
+        let ghost_hit = self.ghost.remove(&key);
+        self.adapt(ghost_hit);
+        // Entries that can't fit in the small queue at all go straight to main too.
+        let queue = if ghost_hit || weight > self.small_size {
+            Queue::Main
         } else {
-            if self.small.len() >= self.small_size {
-                self.evict_small();
+            Queue::Small
+        };
+        if !self.make_room(queue, weight, None) {
+            if ghost_hit {
+                self.ghost.insert(key.clone(), weight);
+            }
+            return Err(Rejected { key, value, weight, reason: RejectionReason::Pinned });
+        }
+        self.record(|s| &s.inserts);
+        if ghost_hit {
+            self.record(|s| &s.ghost_hits);
+        }
+        Ok(self.push_front(queue, key, value, weight, expires))
+    }
+
+    /// Replace the value in a slot, keeping the queue weights up to date.
+    fn replace_value(&mut self, idx: usize, value: V, weight: usize) -> V {
+        let now = self.now();
+        let entry = self.slot_mut(idx);
+        *entry.accessed.get_mut() = now;
+        let old_weight = std::mem::replace(&mut entry.weight, weight);
+        let old = std::mem::replace(&mut entry.value, value);
+        let queue = entry.queue;
+        let list = self.list_mut(queue);
+        list.weight = list.weight - old_weight + weight;
+        old
+    }
+
+    /// Look up a key, counting it as an access.
+    ///
+    /// The key may be any borrowed form of the cache's key type, as with `HashMap`.
+    pub fn read<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let entry = self.slot(self.lookup(key)?);
+        entry.bump(self.max_freq);
+        Some(&entry.value)
+    }
+
+    /// Look up a key without counting it as an access, so it doesn't help the entry stay
+    /// cached, and doesn't show up in the statistics.
+    pub fn peek<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        Some(&self.slot(self.find(key)?).value)
+    }
+
+    /// Whether a key is cached. Like `peek`, this doesn't count as an access.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.find(key).is_some()
+    }
+
+    /// Like `read`, but gives mutable access to the value.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.expire_key(key);
+        let idx = self.lookup(key)?;
+        let max_freq = self.max_freq;
+        let entry = self.slot_mut(idx);
+        entry.bump(max_freq);
+        Some(&mut entry.value)
+    }
+
+    /// Pin a key's entry, so it isn't evicted until it's unpinned as many times as it was pinned.
+    /// Returns false if the key isn't cached.
+    ///
+    /// Eviction skips pinned entries, moving them back to the head of their queue. When there's
+    /// no room for a new entry because every entry in the way is pinned, it's rejected: `insert`
+    /// drops it, and `try_insert` returns it with [`RejectionReason::Pinned`]. A value
+    /// replaced by a heavier one can still take a queue full of pinned entries over its capacity,
+    /// until they're unpinned.
+    ///
+    /// Pinning only protects from eviction: pinned entries still expire, and can be removed.
+    pub fn pin<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let Some(idx) = self.find(key) else { return false };
+        self.pin_slot(idx);
+        true
+    }
+
+    /// Undo one `pin` of a key's entry, evicting from its queue if it was over capacity because
+    /// of pinned entries. Returns false if the key isn't cached or pinned.
+    pub fn unpin<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let Some(&idx) = self.index.get(key) else { return false };
+        if self.slot(idx).pins == 0 {
+            return false;
+        }
+        if self.unpin_slot(idx) {
+            self.make_room(self.slot(idx).queue, 0, None);
+        }
+        true
+    }
+
+    /// Whether a key's entry is pinned, even if it has expired but hasn't been reclaimed yet.
+    pub fn is_pinned<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.index.get(key).is_some_and(|&idx| self.slot(idx).pins > 0)
+    }
+
+    /// Get the entry for a key, for in-place manipulation.
+    ///
+    /// If the key is cached, this counts as an access to it, just like `read`.
+    pub fn entry(&mut self, key: K) -> entry::Entry<'_, K, V> {
+        self.expire_due();
+        self.expire_key(&key);
+        match self.lookup(&key) {
+            Some(idx) => {
+                self.slot(idx).bump(self.max_freq);
+                entry::Entry::Occupied(entry::OccupiedEntry { cache: self, idx })
             }
-            self.small.push_front(Entry::new(key, value));
+            None => entry::Entry::Vacant(entry::VacantEntry { cache: self, key }),
+        }
+    }
+
+    /// Read a value, computing and inserting it if it is not cached.
+    ///
+    /// # Panics
+    ///
+    /// If the computed value is too heavy to ever fit in the cache, or pinned entries leave no
+    /// room for it. Use `try_get_or_insert_with` to handle that case.
+    pub fn get_or_insert_with(&mut self, key: K, f: impl FnOnce() -> V) -> &mut V {
+        self.entry(key).or_insert_with(f)
+    }
+
+    /// Like `get_or_insert_with`, but the value is computed by a fallible function. If it fails,
+    /// nothing is inserted and the error is returned. If the value can't be inserted, because
+    /// it's too heavy or pinned entries are in the way, the [`Rejected`] error is converted into
+    /// an `E`, which can be a `Box<dyn Error>`.
+    pub fn try_get_or_insert_with<E: From<Rejected<K, V>>>(
+        &mut self,
+        key: K,
+        f: impl FnOnce() -> Result<V, E>,
+    ) -> Result<&mut V, E> {
+        match self.entry(key) {
+            entry::Entry::Occupied(e) => Ok(e.into_mut()),
+            entry::Entry::Vacant(e) => Ok(e.try_insert(f()?)?),
         }
     }
 
-    pub fn read(&self, key: &K) -> Option<&V> {
-        if let Some(entry) = self.small.iter()
-            .chain(self.main.iter())
-            .find(|e| &e.key == key)
-        {
-            if entry.freq.fetch_add(1, SeqCst) + 1 > MAX_FREQ {
-                // Clamp it.
-                entry.freq.store(MAX_FREQ, SeqCst);
+    /// Remove a key from the cache, returning its value if it was cached.
+    ///
+    /// The ghost queue is left alone, so if the key was recently evicted from the small queue, it
+    /// will still go straight into the main queue when inserted again. Use `purge` to forget it.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.expire_key(key);
+        let idx = self.index.remove(key)?;
+        self.unlink(idx);
+        let entry = self.release(idx);
+        self.notify(&entry.key, &entry.value, RemovalCause::Removed);
+        Some(entry.value)
+    }
+
+    /// Remove a key from the cache, and also from the ghost queue.
+    pub fn purge<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.ghost.remove(key);
+        self.remove(key)
+    }
+
+    /// Keep only the entries for which the predicate returns true. The ghost queue is unchanged.
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        for idx in 0 .. self.slots.len() {
+            let Some(entry) = &mut self.slots[idx] else { continue };
+            if !f(&entry.key, &mut entry.value) {
+                self.unlink(idx);
+                let entry = self.release(idx);
+                self.index.remove(&entry.key);
+                self.notify(&entry.key, &entry.value, RemovalCause::Removed);
             }
-            Some(&entry.value)
-        } else {
-            None
         }
     }
 
-    fn evict_main(&mut self) {
-        while let Some(tail) = self.main.pop_back() {
-            let n = tail.freq.load(SeqCst);
-            if n > 0 {
-                tail.freq.store(n - 1, SeqCst);
-                self.main.push_front(tail);
+    /// Remove every entry, but remember the ghost queue, so previously popular keys are still
+    /// recognized when they come back.
+    pub fn invalidate_all(&mut self) {
+        let slots = std::mem::take(&mut self.slots);
+        self.free.clear();
+        self.index.clear();
+        self.expirations.clear();
+        self.small = List::new();
+        self.main = List::new();
+        for entry in slots.into_iter().flatten() {
+            self.notify(&entry.key, &entry.value, RemovalCause::Cleared);
+        }
+    }
+
+    /// Remove every entry and forget the ghost queue too, leaving the cache as if it was new.
+    pub fn clear(&mut self) {
+        self.invalidate_all();
+        self.ghost.clear();
+    }
+
+    /// Remove every expired entry now, rather than waiting for them to be reclaimed while
+    /// inserting or evicting.
+    ///
+    /// Entries expired by their time to live are reclaimed on every insertion anyway, but ones
+    /// which went idle are only found when they reach the end of their queue, or by this.
+    pub fn remove_expired(&mut self) {
+        self.expire_due();
+        for idx in 0 .. self.slots.len() {
+            if self.slots[idx].as_ref().is_some_and(|entry| self.is_expired(entry)) {
+                let entry = self.expire(idx);
+                self.hand_over(entry, None);
+            }
+        }
+    }
+
+    /// Estimated probability that a key is mistaken for one in the ghost queue, when it only
+    /// holds fingerprints of keys. It grows with the number of keys the ghost queue holds, and
+    /// is always 0 when it holds the keys themselves. See
+    /// [`S3FifoBuilder::ghost_fingerprints`].
+    pub fn ghost_false_positive_rate(&self) -> f64 {
+        self.ghost.false_positive_rate()
+    }
+
+    /// Number of cached entries, which may include expired entries not reclaimed yet.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Total weight of all the cached entries. Without a weigher, this is the same as `len`.
+    pub fn weight(&self) -> usize {
+        self.small.weight + self.main.weight
+    }
+
+    /// Change the capacity of the small and main queues, and of the ghost queue, all as total
+    /// weights. Entries which no longer fit are evicted as they would be to make room for new
+    /// ones, so entries which were accessed get promoted or reinserted rather than evicted. The
+    /// evicted entries are returned, after the eviction listener has seen them.
+    ///
+    /// Growing doesn't allocate anything up front; storage grows as entries are inserted.
+    pub fn set_capacities(&mut self, small: usize, main: usize, ghost: usize) -> Vec<(K, V)> {
+        self.small_size = small;
+        self.main_size = main;
+        self.ghost.set_capacity(ghost);
+        let mut evicted = Vec::new();
+        // Main first, so there's room for what gets promoted from small.
+        self.make_room(Queue::Main, 0, Some(&mut evicted));
+        self.make_room(Queue::Small, 0, Some(&mut evicted));
+        evicted
+    }
+
+    /// Change the total capacity, keeping the proportions of the small, main and ghost queues.
+    /// See `set_capacities`.
+    pub fn resize(&mut self, total: usize) -> Vec<(K, V)> {
+        let (small, main) = split_capacity(total, self.small_size, self.main_size);
+        let ghost = match self.main_size {
+            0 => main,
+            old => (self.ghost.capacity() as u128 * main as u128 / old as u128) as usize,
+        };
+        self.set_capacities(small, main, ghost)
+    }
+
+    /// Feed an insertion to the adaptive split, and move capacity between the queues if it says
+    /// so.
+    fn adapt(&mut self, ghost_hit: bool) {
+        let Some(adaptive) = &mut self.adaptive else { return };
+        if ghost_hit {
+            adaptive.ghost_hit();
+        }
+        let total = self.small_size + self.main_size;
+        if let Some(small) = adaptive.insert(self.index.len(), self.small_size, total) {
+            self.small_size = small;
+            self.main_size = total - small;
+            self.make_room(Queue::Main, 0, None);
+            self.make_room(Queue::Small, 0, None);
+        }
+    }
+
+    /// Evict from a queue until an entry of the given weight fits in it, adding the evicted
+    /// entries to `evicted` if given. Returns false if pinned entries are in the way.
+    fn make_room(
+        &mut self,
+        queue: Queue,
+        weight: usize,
+        mut evicted: Option<&mut Vec<(K, V)>>,
+    ) -> bool {
+        match queue {
+            Queue::Small => {
+                while self.small.tail != NIL && self.small.weight + weight > self.small_size {
+                    if !self.evict_small(evicted.as_deref_mut()) {
+                        return false;
+                    }
+                }
+            }
+            Queue::Main => {
+                while self.main.tail != NIL && self.main.weight + weight > self.main_size {
+                    if !self.evict_main(evicted.as_deref_mut()) {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Evict an entry from the main queue, or return false if they're all pinned.
+    fn evict_main(&mut self, evicted: Option<&mut Vec<(K, V)>>) -> bool {
+        if self.main.pinned == self.main.len {
+            return false;
+        }
+        // There's an unpinned entry, and each pass over the queue lowers its frequency, so this
+        // ends.
+        while self.main.tail != NIL {
+            let tail = self.main.tail;
+            let entry = self.slot(tail);
+            let n = entry.freq.load(SeqCst);
+            if self.is_expired(entry) {
+                let entry = self.expire(tail);
+                self.hand_over(entry, evicted);
+                break;
+            } else if entry.pins > 0 {
+                self.unlink(tail);
+                self.link_front(Queue::Main, tail);
+            } else if n > 0 {
+                entry.freq.store(n - 1, SeqCst);
+                self.record(|s| &s.reinsertions);
+                if let Some(adaptive) = &mut self.adaptive {
+                    adaptive.reinsertion();
+                }
+                self.unlink(tail);
+                self.link_front(Queue::Main, tail);
             } else {
+                self.unlink(tail);
+                let entry = self.release(tail);
+                self.index.remove(&entry.key);
+                self.record(|s| &s.evictions);
+                if let Some(adaptive) = &mut self.adaptive {
+                    adaptive.main_eviction();
+                }
+                self.notify(&entry.key, &entry.value, RemovalCause::EvictedMain);
+                self.hand_over((entry.key, entry.value), evicted);
                 break;
             }
         }
+        true
     }
 
-    fn evict_small(&mut self) {
-        if let Some(tail) = self.small.pop_back() {
-            if tail.freq.load(SeqCst) > 1 {
-                if self.main.len() >= self.main_size {
-                    self.evict_main();
+    /// Evict or promote the entry at the tail of the small queue, moving pinned entries which
+    /// can't be promoted back to the head, or return false if that's all of them.
+    fn evict_small(&mut self, mut evicted: Option<&mut Vec<(K, V)>>) -> bool {
+        for _ in 0 .. self.small.len {
+            let tail = self.small.tail;
+            let entry = self.slot(tail);
+            if self.is_expired(entry) {
+                let entry = self.expire(tail);
+                self.hand_over(entry, evicted);
+                return true;
+            }
+            // Make room before moving the entry, so it's never left out of both queues if an
+            // eviction listener panics.
+            if entry.freq.load(SeqCst) >= self.promotion_threshold
+                && self.make_room(Queue::Main, entry.weight, evicted.as_deref_mut())
+            {
+                if self.reset_on_promotion {
+                    self.slot(tail).freq.store(0, SeqCst);
                 }
-                self.main.push_front(tail);
+                self.unlink(tail);
+                self.link_front(Queue::Main, tail);
+                self.record(|s| &s.promotions);
+                return true;
+            }
+            if self.slot(tail).pins > 0 {
+                self.unlink(tail);
+                self.link_front(Queue::Small, tail);
+                continue;
+            }
+            self.unlink(tail);
+            let entry = self.release(tail);
+            self.index.remove(&entry.key);
+            self.record(|s| &s.demotions);
+            if let Some(adaptive) = &mut self.adaptive {
+                adaptive.small_eviction();
+            }
+            self.notify(&entry.key, &entry.value, RemovalCause::EvictedSmall);
+            if evicted.is_some() || self.kept.is_some() {
+                self.ghost.insert(entry.key.clone(), entry.weight);
+                self.hand_over((entry.key, entry.value), evicted);
             } else {
-                if self.ghost.len() >= self.main_size {
-                    self.ghost.pop_back();
+                self.ghost.insert(entry.key, entry.weight);
+            }
+            return true;
+        }
+        false
+    }
+
+    /// Find the slot of a key, unless it has expired.
+    fn find<Q>(&self, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let idx = *self.index.get(key)?;
+        Some(idx).filter(|&idx| !self.is_expired(self.slot(idx)))
+    }
+
+    /// Like `find`, but counting a hit or a miss, and the access time.
+    fn lookup<Q>(&self, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let idx = self.find(key);
+        match idx {
+            Some(idx) => {
+                self.record(|s| &s.hits);
+                if self.time_to_idle.is_some() {
+                    self.slot(idx).accessed.store(self.now(), SeqCst);
                 }
-                self.ghost.push_front(tail.key);
             }
+            None => self.record(|s| &s.misses),
+        }
+        idx
+    }
+
+    fn now(&self) -> u64 {
+        nanos(self.clock.now())
+    }
+
+    /// Deadline for an entry written now, with the given time to live or the default one.
+    fn deadline(&self, ttl: Option<Duration>) -> u64 {
+        match ttl.or(self.time_to_live) {
+            Some(ttl) => self.now().saturating_add(nanos(ttl)),
+            None => NEVER,
+        }
+    }
+
+    fn is_expired(&self, entry: &Entry<K, V>) -> bool {
+        if entry.expires == NEVER && self.time_to_idle.is_none() {
+            return false;
+        }
+        let now = self.now();
+        let idle = self.time_to_idle.is_some_and(|tti| {
+            entry.accessed.load(SeqCst).saturating_add(nanos(tti)) <= now
+        });
+        idle || entry.expires <= now
+    }
+
+    fn set_expiry(&mut self, idx: usize, expires: u64) {
+        let old = std::mem::replace(&mut self.slot_mut(idx).expires, expires);
+        if old != NEVER {
+            self.expirations.remove(&(old, idx));
+        }
+        if expires != NEVER {
+            self.expirations.insert((expires, idx));
+        }
+    }
+
+    /// Reclaim the entries whose time to live has run out.
+    fn expire_due(&mut self) {
+        if self.expirations.is_empty() {
+            return;
+        }
+        let now = self.now();
+        while let Some(&(expires, idx)) = self.expirations.first() {
+            if expires > now {
+                break;
+            }
+            let entry = self.expire(idx);
+            self.hand_over(entry, None);
+        }
+    }
+
+    /// Reclaim a key's entry if it has expired.
+    fn expire_key<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if let Some(&idx) = self.index.get(key) {
+            if self.is_expired(self.slot(idx)) {
+                let entry = self.expire(idx);
+                self.hand_over(entry, None);
+            }
+        }
+    }
+
+    fn expire(&mut self, idx: usize) -> (K, V) {
+        self.unlink(idx);
+        let entry = self.release(idx);
+        self.index.remove(&entry.key);
+        self.record(|s| &s.expirations);
+        self.notify(&entry.key, &entry.value, RemovalCause::Expired);
+        (entry.key, entry.value)
+    }
+
+    /// Pass on an entry which was evicted or expired, to `evicted` if given, or else to the kept
+    /// entries if they're being kept. Otherwise, it's dropped.
+    fn hand_over(&mut self, entry: (K, V), evicted: Option<&mut Vec<(K, V)>>) {
+        match (evicted, &mut self.kept) {
+            (Some(out), _) | (None, Some(out)) => out.push(entry),
+            (None, None) => {}
+        }
+    }
+
+    /// From now on, keep the entries which are evicted or expire, rather than dropping them,
+    /// until they're taken with `take_evicted`.
+    pub(crate) fn keep_evicted(&mut self) {
+        self.kept.get_or_insert_with(Vec::new);
+    }
+
+    pub(crate) fn take_evicted(&mut self) -> Vec<(K, V)> {
+        self.kept.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    fn record(&self, counter: impl FnOnce(&Counters) -> &AtomicU64) {
+        if let Some(stats) = &self.stats {
+            stats::count(counter(stats));
         }
     }
+
+    fn notify(&self, key: &K, value: &V, cause: RemovalCause) {
+        if let Some(listener) = &self.listener {
+            listener.on_removal(key, value, cause);
+        }
+    }
+
+    fn push_front(
+        &mut self,
+        queue: Queue,
+        key: K,
+        value: V,
+        weight: usize,
+        expires: u64,
+    ) -> usize {
+        let entry = Entry::new(key.clone(), value, weight, queue, self.now(), expires);
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.slots[idx] = Some(entry);
+                idx
+            }
+            None => {
+                self.slots.push(Some(entry));
+                self.slots.len() - 1
+            }
+        };
+        self.link_front(queue, idx);
+        self.index.insert(key, idx);
+        if expires != NEVER {
+            self.expirations.insert((expires, idx));
+        }
+        idx
+    }
+
+    fn pin_slot(&mut self, idx: usize) {
+        let entry = self.slot_mut(idx);
+        entry.pins += 1;
+        if entry.pins == 1 {
+            let queue = entry.queue;
+            self.list_mut(queue).pinned += 1;
+        }
+    }
+
+    /// Undo a `pin_slot`, returning whether the entry is no longer pinned at all.
+    fn unpin_slot(&mut self, idx: usize) -> bool {
+        let entry = self.slot_mut(idx);
+        entry.pins -= 1;
+        if entry.pins > 0 {
+            return false;
+        }
+        let queue = entry.queue;
+        self.list_mut(queue).pinned -= 1;
+        true
+    }
+
+    fn release(&mut self, idx: usize) -> Entry<K, V> {
+        let entry = self.slots[idx].take().expect("released an empty slot");
+        if entry.expires != NEVER {
+            self.expirations.remove(&(entry.expires, idx));
+        }
+        self.free.push(idx);
+        entry
+    }
+
+    fn slot(&self, idx: usize) -> &Entry<K, V> {
+        self.slots[idx].as_ref().expect("dangling slot index")
+    }
+
+    fn slot_mut(&mut self, idx: usize) -> &mut Entry<K, V> {
+        self.slots[idx].as_mut().expect("dangling slot index")
+    }
+
+    fn list_mut(&mut self, queue: Queue) -> &mut List {
+        match queue {
+            Queue::Small => &mut self.small,
+            Queue::Main => &mut self.main,
+        }
+    }
+
+    fn link_front(&mut self, queue: Queue, idx: usize) {
+        let head = self.list_mut(queue).head;
+        let (weight, pinned) = {
+            let entry = self.slot_mut(idx);
+            entry.queue = queue;
+            entry.prev = NIL;
+            entry.next = head;
+            (entry.weight, entry.pins > 0)
+        };
+        if head != NIL {
+            self.slot_mut(head).prev = idx;
+        }
+        let list = self.list_mut(queue);
+        list.head = idx;
+        if list.tail == NIL {
+            list.tail = idx;
+        }
+        list.len += 1;
+        list.weight += weight;
+        list.pinned += usize::from(pinned);
+    }
+
+    fn unlink(&mut self, idx: usize) {
+        let (queue, prev, next, weight, pinned) = {
+            let entry = self.slot_mut(idx);
+            let links = (entry.queue, entry.prev, entry.next, entry.weight, entry.pins > 0);
+            entry.prev = NIL;
+            entry.next = NIL;
+            links
+        };
+        if prev != NIL {
+            self.slot_mut(prev).next = next;
+        } else {
+            self.list_mut(queue).head = next;
+        }
+        if next != NIL {
+            self.slot_mut(next).prev = prev;
+        } else {
+            self.list_mut(queue).tail = prev;
+        }
+        let list = self.list_mut(queue);
+        list.len -= 1;
+        list.weight -= weight;
+        list.pinned -= usize::from(pinned);
+    }
+}
+
+#[cfg(test)]
+impl<K: Hash + Eq + Clone + std::fmt::Debug, V> S3Fifo<K, V> {
+    /// Keys of the given queue, from head to tail.
+    fn queue_keys(&self, queue: Queue) -> Vec<&K> {
+        let mut keys = vec![];
+        let mut idx = match queue {
+            Queue::Small => self.small.head,
+            Queue::Main => self.main.head,
+        };
+        while idx != NIL {
+            let entry = self.slot(idx);
+            keys.push(&entry.key);
+            idx = entry.next;
+        }
+        keys
+    }
+
+    fn check_invariants(&self) {
+        let small = self.queue_keys(Queue::Small);
+        let main = self.queue_keys(Queue::Main);
+        assert_eq!(small.len(), self.small.len);
+        assert_eq!(main.len(), self.main.len);
+        assert_eq!(small.len() + main.len(), self.index.len());
+        let queues = [(Queue::Small, &small, self.small), (Queue::Main, &main, self.main)];
+        for (queue, keys, list) in queues {
+            let mut weight = 0;
+            for key in keys.iter() {
+                let entry = self.slot(self.index[*key]);
+                assert_eq!(&entry.key, *key);
+                assert_eq!(entry.queue, queue);
+                assert_eq!(entry.weight, self.weigher.weigh(&entry.key, &entry.value));
+                weight += entry.weight;
+            }
+            assert_eq!(weight, list.weight);
+            let pinned = keys.iter().filter(|key| self.is_pinned(**key)).count();
+            assert_eq!(pinned, list.pinned);
+        }
+        assert!(self.main.weight <= self.main_size || self.main.pinned > 0);
+        assert_eq!(
+            self.slots.iter().filter(|s| s.is_some()).count() + self.free.len(),
+            self.slots.len()
+        );
+        for &(expires, idx) in &self.expirations {
+            assert_eq!(self.slot(idx).expires, expires);
+        }
+        let deadlines = self.slots.iter().flatten().filter(|e| e.expires != NEVER).count();
+        assert_eq!(deadlines, self.expirations.len());
+    }
 }
 
 #[cfg(test)]
@@ -113,6 +1094,7 @@ mod tests {
     fn it_works() {
         let mut rng = rand::rngs::StdRng::seed_from_u64(0);
         let mut q = S3Fifo::<u32, u32>::new(2, 20);
+        q.enable_stats();
 
         let mut hit_rate = (0, 0);
         for i in 0 .. 10_000 {
@@ -129,19 +1111,565 @@ mod tests {
                     }
                     None => {
                         eprintln!("miss");
-                        assert!( q.main.iter().chain(q.small.iter()).find(|e| e.key == k).is_none());
+                        assert!(!q.queue_keys(Queue::Main).contains(&&k));
+                        assert!(!q.queue_keys(Queue::Small).contains(&&k));
                         hit_rate.1 += 1;
                     }
                 }
             } else {
                 eprintln!("insert {k}");
-                q.insert(k, k);
+                let cached = q.index.contains_key(&k);
+                assert_eq!(q.insert(k, k).is_some(), cached);
             }
-            assert!(q.main.len() <= q.main_size);
-            assert!(q.small.len() <= q.small_size);
+            assert!(q.main.len <= q.main_size);
+            assert!(q.small.len <= q.small_size);
             assert!(q.ghost.len() <= q.main_size);
+            q.check_invariants();
         }
         let (n, d) = hit_rate;
         println!("{n}/{d} = {}", (n as f64) / (d as f64));
+        let stats = q.stats();
+        assert_eq!((stats.hits, stats.hits + stats.misses), hit_rate);
+        assert_eq!(stats.hit_rate(), (n as f64) / (d as f64));
+    }
+
+    #[test]
+    fn large_cache() {
+        // With linear scans this takes minutes; with the index it should be instant.
+        let n = 200_000;
+        let mut q = S3Fifo::<u64, u64>::new(n / 10, n);
+        for k in 0 .. n as u64 * 2 {
+            q.insert(k, k);
+            assert_eq!(q.read(&(k / 2)).is_some(), q.index.contains_key(&(k / 2)));
+        }
+        for k in 0 .. n as u64 {
+            q.insert(k, k + 1);
+            assert_eq!(q.read(&k), Some(&(k + 1)));
+        }
+        q.check_invariants();
+    }
+
+    #[test]
+    fn upsert() {
+        let mut q = S3Fifo::<u32, &str>::new(2, 4);
+        assert_eq!(q.insert(1, "a"), None);
+        assert_eq!(q.read(&1), Some(&"a"));
+        assert_eq!(q.read(&1), Some(&"a"));
+        assert_eq!(q.insert(1, "b"), Some("a"));
+        assert_eq!(q.read(&1), Some(&"b"));
+        assert_eq!(q.len(), 1);
+
+        // Replacing keeps the frequency, so the entry is promoted to main when it's evicted from
+        // small.
+        assert_eq!(q.slot(q.index[&1]).freq.load(SeqCst), 3);
+        q.insert(2, "x");
+        q.insert(3, "x");
+        assert_eq!(q.queue_keys(Queue::Main), vec![&1]);
+        assert_eq!(q.queue_keys(Queue::Small), vec![&3, &2]);
+
+        // Replacing with a reset doesn't.
+        assert_eq!(q.insert_reset(1, "c"), Some("b"));
+        assert_eq!(q.slot(q.index[&1]).freq.load(SeqCst), 0);
+        assert_eq!(q.insert(3, "y"), Some("x"));
+        assert_eq!(q.queue_keys(Queue::Small), vec![&3, &2]);
+        q.check_invariants();
+    }
+
+    #[test]
+    fn keys_are_unique() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let mut q = S3Fifo::<u32, u32>::new(3, 10);
+        for _ in 0 .. 10_000 {
+            let k = rng.gen_range(0..30);
+            if rng.gen_bool(0.3) {
+                q.read(&k);
+            } else {
+                q.insert(k, rng.gen());
+            }
+            let mut keys = q.queue_keys(Queue::Small);
+            keys.extend(q.queue_keys(Queue::Main));
+            let n = keys.len();
+            keys.sort();
+            keys.dedup();
+            assert_eq!(keys.len(), n);
+        }
+    }
+
+    #[test]
+    fn remove() {
+        let mut q = S3Fifo::<u32, u32>::new(2, 4);
+        q.insert(1, 10);
+        q.insert(2, 20);
+        q.insert(3, 30); // evicts 1 to the ghost queue
+        q.read(&3);
+        q.read(&3);
+        q.insert(4, 40); // evicts 2 to the ghost queue too
+        assert_eq!(q.queue_keys(Queue::Small), vec![&4, &3]);
+
+        assert_eq!(q.remove(&3), Some(30));
+        assert_eq!(q.remove(&3), None);
+        assert_eq!(q.read(&3), None);
+        assert_eq!(q.queue_keys(Queue::Small), vec![&4]);
+        q.check_invariants();
+
+        // 1 is still in the ghost queue after a removal, but not after a purge.
+        assert_eq!(q.remove(&1), None);
+        assert_eq!(q.purge(&2), None);
+        q.insert(1, 10);
+        q.insert(2, 20);
+        assert_eq!(q.queue_keys(Queue::Main), vec![&1]);
+        assert_eq!(q.queue_keys(Queue::Small), vec![&2, &4]);
+        assert_eq!(q.purge(&1), Some(10));
+        q.check_invariants();
+    }
+
+    #[test]
+    fn retain_and_clear() {
+        let mut q = S3Fifo::<u32, u32>::new(5, 5);
+        for k in 0 .. 5 {
+            q.insert(k, k);
+            q.read(&k);
+            q.read(&k);
+        }
+        for k in 5 .. 8 {
+            q.insert(k, k);
+        }
+        assert_eq!(q.queue_keys(Queue::Main).len(), 3);
+        q.retain(|k, v| {
+            *v += 100;
+            k % 2 == 0
+        });
+        assert_eq!(q.len(), 4);
+        for k in [0, 2, 4, 6] {
+            assert_eq!(q.read(&k), Some(&(k + 100)));
+        }
+        q.check_invariants();
+
+        // 6 gets evicted to the ghost queue, which invalidate_all remembers, but clear doesn't.
+        for k in 10 .. 15 {
+            q.insert(k, k);
+        }
+        assert!(q.read(&6).is_none());
+        q.invalidate_all();
+        assert!(q.is_empty());
+        q.check_invariants();
+        q.insert(6, 6);
+        assert_eq!(q.queue_keys(Queue::Main), vec![&6]);
+        q.insert(13, 13);
+        q.clear();
+        q.insert(13, 13);
+        assert_eq!(q.queue_keys(Queue::Small), vec![&13]);
+        q.check_invariants();
+    }
+
+    #[test]
+    fn borrowed_lookups() {
+        let mut q = S3Fifo::<String, u32>::new(2, 4);
+        q.enable_stats();
+        q.insert("a".to_string(), 1);
+        q.insert("b".to_string(), 2);
+        assert_eq!(q.peek("a"), Some(&1));
+        assert!(q.contains_key("a"));
+        assert!(!q.contains_key("c"));
+        assert_eq!(q.slot(q.index["a"]).freq.load(SeqCst), 0);
+        assert_eq!((q.stats().hits, q.stats().misses), (0, 0));
+
+        assert_eq!(q.read("a"), Some(&1));
+        *q.get_mut("a").unwrap() += 10;
+        assert_eq!(q.slot(q.index["a"]).freq.load(SeqCst), 2);
+        // Peeking doesn't save b from eviction.
+        q.peek("b");
+        q.insert("c".to_string(), 3);
+        q.insert("d".to_string(), 4);
+        assert_eq!(q.peek("b"), None);
+        assert_eq!(q.queue_keys(Queue::Main), vec!["a"]);
+
+        assert_eq!(q.remove("a"), Some(11));
+        assert_eq!(q.purge("b"), None);
+        q.insert("b".to_string(), 2);
+        // It was purged from the ghost queue too, so it's back in small.
+        assert_eq!(q.queue_keys(Queue::Small), vec!["b", "d"]);
+    }
+
+    #[test]
+    fn entry_api() {
+        let mut q = S3Fifo::<u32, u32>::new(2, 4);
+        *q.entry(1).or_insert(10) += 1;
+        assert_eq!(q.read(&1), Some(&11));
+        // Occupied entries count as an access, like read does.
+        assert_eq!(q.slot(q.index[&1]).freq.load(SeqCst), 1);
+        match q.entry(1) {
+            entry::Entry::Occupied(mut e) => {
+                assert_eq!(e.freq(), 2);
+                assert_eq!(e.insert(12), 11);
+            }
+            entry::Entry::Vacant(_) => panic!("1 should be cached"),
+        }
+        assert_eq!(*q.get_or_insert_with(1, || unreachable!()), 12);
+        assert_eq!(*q.get_or_insert_with(2, || 20), 20);
+        *q.get_mut(&2).unwrap() += 1;
+        assert_eq!(q.read(&2), Some(&21));
+        assert_eq!(q.get_mut(&3), None);
+
+        type Error = Box<dyn std::error::Error>;
+        let failed = q.try_get_or_insert_with::<Error>(3, || Err("nope".into()));
+        assert_eq!(failed.unwrap_err().to_string(), "nope");
+        assert_eq!(q.len(), 2);
+        assert_eq!(q.try_get_or_insert_with::<Error>(3, || Ok(30)).ok(), Some(&mut 30));
+        // Inserting 3 evicted 1 from the small queue, and it was read enough to be promoted.
+        assert_eq!(q.queue_keys(Queue::Main), vec![&1]);
+        assert_eq!(q.queue_keys(Queue::Small), vec![&3, &2]);
+
+        match q.entry(2) {
+            entry::Entry::Occupied(e) => assert_eq!(e.remove_entry(), (2, 21)),
+            entry::Entry::Vacant(_) => panic!("2 should be cached"),
+        }
+        assert_eq!(q.read(&2), None);
+        q.check_invariants();
+    }
+
+    #[test]
+    fn entry_goes_through_ghost() {
+        let mut q = S3Fifo::<u32, u32>::new(1, 4);
+        q.insert(1, 1);
+        q.insert(2, 2); // evicts 1 to the ghost queue
+        q.entry(1).or_insert(1);
+        assert_eq!(q.queue_keys(Queue::Main), vec![&1]);
+        assert_eq!(q.queue_keys(Queue::Small), vec![&2]);
+        q.check_invariants();
+    }
+
+    #[test]
+    fn resize() {
+        let mut q = S3Fifo::new(2, 4);
+        q.insert(1, 1);
+        q.insert(2, 2);
+        q.read(&1);
+        q.read(&1);
+        for k in [3, 4, 5, 2, 3] {
+            q.insert(k, k);
+        }
+        q.read(&3);
+        assert_eq!(q.queue_keys(Queue::Small), vec![&5, &4]);
+        assert_eq!(q.queue_keys(Queue::Main), vec![&3, &2, &1]);
+
+        // 1 was read, so it goes round main again instead of being evicted.
+        assert_eq!(q.set_capacities(1, 2, 4), vec![(2, 2), (4, 4)]);
+        assert_eq!(q.queue_keys(Queue::Small), vec![&5]);
+        assert_eq!(q.queue_keys(Queue::Main), vec![&1, &3]);
+        q.check_invariants();
+
+        assert_eq!(q.resize(30), vec![]);
+        assert_eq!((q.small_size, q.main_size, q.ghost.capacity()), (10, 20, 40));
+        assert_eq!(q.resize(0).len(), 3);
+        assert!(q.is_empty());
+        q.check_invariants();
+    }
+
+    #[test]
+    fn stats() {
+        let mut q = S3Fifo::new(1, 1);
+        q.insert(1, 1);
+        let sizes = Stats {
+            small_size: 1,
+            main_size: 1,
+            ..Stats::default()
+        };
+        assert_eq!(q.stats(), sizes);
+
+        q.enable_stats();
+        q.insert(2, 2);
+        q.insert(1, 1);
+        q.read(&1);
+        q.insert(3, 3);
+        // Main is full, so 1 goes round once before being evicted.
+        q.insert(2, 2);
+        assert_eq!(q.read(&1), None);
+        q.read(&3);
+        q.read(&3);
+        // Promoting 3 evicts 2 from main.
+        q.insert(4, 4);
+        assert_eq!(
+            q.stats(),
+            Stats {
+                hits: 3,
+                misses: 1,
+                inserts: 5,
+                ghost_hits: 2,
+                promotions: 1,
+                demotions: 2,
+                reinsertions: 1,
+                evictions: 2,
+                expirations: 0,
+                ..sizes
+            }
+        );
+        q.reset_stats();
+        assert_eq!(q.stats(), sizes);
+    }
+
+    #[test]
+    fn expiration() {
+        use std::sync::Arc;
+
+        let secs = Duration::from_secs;
+        let clock = Arc::new(ManualClock::new());
+        let mut q = S3Fifo::new(5, 10);
+        q.set_clock(Arc::clone(&clock));
+        q.enable_stats();
+        q.set_time_to_live(Some(secs(10)));
+        q.insert(1, 1);
+        q.insert_with_ttl(2, 2, secs(5));
+        q.insert_with_ttl(3, 3, secs(20));
+        clock.advance(secs(5));
+        // Expired entries are misses, even before they're reclaimed.
+        assert_eq!(q.read(&2), None);
+        assert_eq!(q.len(), 3);
+        assert_eq!(q.read(&1), Some(&1));
+        // Replacing a value gives it a new deadline.
+        q.insert(1, 10);
+        clock.advance(secs(5));
+        assert_eq!(q.read(&1), Some(&10));
+        // Inserting reclaims what has expired.
+        q.insert(4, 4);
+        assert_eq!(q.len(), 3);
+        clock.advance(secs(5));
+        assert_eq!(q.read(&1), None);
+        assert_eq!(q.remove(&1), None);
+        assert_eq!(q.get_mut(&1), None);
+        assert_eq!(q.len(), 2);
+        assert_eq!(q.read(&3), Some(&3));
+        assert_eq!(q.stats().expirations, 2);
+        q.check_invariants();
+
+        // Idle entries expire without being read, but reads keep them alive.
+        let mut q = S3Fifo::new(2, 4);
+        q.set_clock(Arc::clone(&clock));
+        q.set_time_to_idle(Some(secs(10)));
+        q.insert(1, 1);
+        q.insert(2, 2);
+        clock.advance(secs(6));
+        q.read(&1);
+        clock.advance(secs(6));
+        assert_eq!(q.read(&1), Some(&1));
+        assert_eq!(q.read(&2), None);
+        q.remove_expired();
+        assert_eq!(q.queue_keys(Queue::Small), vec![&1]);
+
+        // Eviction drops expired entries instead of promoting or reinserting them.
+        q.insert(2, 2);
+        q.read(&1);
+        q.read(&2);
+        q.read(&2);
+        clock.advance(secs(20));
+        q.insert(3, 3);
+        assert_eq!(q.queue_keys(Queue::Small), vec![&3, &2]);
+        assert!(q.queue_keys(Queue::Main).is_empty());
+        q.check_invariants();
+    }
+
+    #[test]
+    fn eviction_listener() {
+        use std::sync::{Arc, Mutex};
+        use RemovalCause::*;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let record = |events: &Arc<Mutex<Vec<_>>>| {
+            let events = Arc::clone(events);
+            move |k: &u32, v: &u32, cause| events.lock().unwrap().push((*k, *v, cause))
+        };
+        let take = || std::mem::take(&mut *events.lock().unwrap());
+
+        let mut q = S3Fifo::new(2, 4);
+        q.set_eviction_listener(record(&events));
+        q.insert(1, 10);
+        q.insert(2, 20);
+        q.insert(3, 30);
+        assert_eq!(take(), vec![(1, 10, EvictedSmall)]);
+        q.read(&2);
+        q.read(&2);
+        // Promotions aren't removals.
+        q.insert(4, 40);
+        assert_eq!(take(), vec![]);
+        q.insert(4, 41);
+        q.remove(&3);
+        q.retain(|&k, _| k != 2);
+        assert_eq!(take(), vec![(4, 40, Replaced), (3, 30, Removed), (2, 20, Removed)]);
+        if let entry::Entry::Occupied(mut e) = q.entry(4) {
+            e.insert(42);
+            e.remove();
+        }
+        assert_eq!(take(), vec![(4, 41, Replaced), (4, 42, Removed)]);
+        q.insert(1, 11);
+        q.insert(5, 50);
+        q.invalidate_all();
+        let mut cleared = take();
+        cleared.sort_by_key(|e| e.0);
+        assert_eq!(cleared, vec![(1, 11, Cleared), (5, 50, Cleared)]);
+
+        let mut q = S3Fifo::new(1, 1);
+        q.set_eviction_listener(record(&events));
+        q.insert(1, 10);
+        q.insert(2, 20);
+        q.insert(1, 11);
+        q.insert(3, 30);
+        q.insert(2, 21);
+        assert_eq!(
+            take(),
+            vec![(1, 10, EvictedSmall), (2, 20, EvictedSmall), (1, 11, EvictedMain)]
+        );
+    }
+
+    #[test]
+    fn panicking_listener() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let mut q = S3Fifo::new(1, 2);
+        q.insert(1, 1);
+        q.read(&1);
+        q.read(&1);
+        q.insert(2, 2);
+        q.set_eviction_listener(|_: &u32, _: &u32, _| panic!("listener panicked"));
+        fn panics(q: &mut S3Fifo<u32, u32>, f: impl FnOnce(&mut S3Fifo<u32, u32>)) {
+            assert!(catch_unwind(AssertUnwindSafe(|| f(q))).is_err());
+            q.check_invariants();
+        }
+
+        // Evicting 2 panics before 3 gets inserted.
+        panics(&mut q, |q| {
+            q.insert(3, 3);
+        });
+        panics(&mut q, |q| {
+            q.insert(1, 10);
+        });
+        panics(&mut q, |q| {
+            q.insert(3, 3);
+            q.remove(&3);
+        });
+        assert_eq!(q.queue_keys(Queue::Main), vec![&1]);
+        assert_eq!(q.read(&1), Some(&10));
+        assert_eq!(q.len(), 1);
+        panics(&mut q, |q| q.clear());
+        assert!(q.is_empty());
+        q.insert(4, 4);
+        assert_eq!(q.read(&4), Some(&4));
+    }
+
+    #[test]
+    fn pinning() {
+        let mut q = S3Fifo::<u32, u32>::new(2, 2);
+        q.insert(1, 1);
+        q.insert(2, 2);
+        assert!(q.pin(&1) && q.pin(&1));
+        assert!(!q.pin(&5));
+        // 1 is skipped rather than evicted.
+        q.insert(3, 3);
+        assert_eq!(q.queue_keys(Queue::Small), [&3, &1]);
+        // With the whole small queue pinned, and nothing read enough to be promoted, there's no
+        // room for more.
+        assert!(q.pin(&3));
+        let no_room = "no room for entry of weight 1 beside the pinned entries";
+        let rejected = q.try_insert(4, 4).unwrap_err();
+        assert_eq!(rejected.reason, RejectionReason::Pinned);
+        assert_eq!(rejected.to_string(), no_room);
+        assert_eq!(q.insert(4, 4), None);
+        // Loading through the cache gets the rejection back, rather than panicking.
+        let loaded = q.try_get_or_insert_with::<Box<dyn std::error::Error>>(4, || Ok(4));
+        assert_eq!(loaded.unwrap_err().to_string(), no_room);
+        assert!(!q.contains_key(&4));
+        // Pinned entries still get promoted.
+        q.read(&3);
+        q.read(&3);
+        q.insert(4, 4);
+        assert_eq!(q.queue_keys(Queue::Main), [&3]);
+        assert_eq!(q.queue_keys(Queue::Small), [&4, &1]);
+        q.check_invariants();
+        assert!(q.unpin(&1) && q.is_pinned(&1));
+        assert!(q.unpin(&1) && !q.is_pinned(&1));
+        assert!(!q.unpin(&1));
+        q.insert(5, 5);
+        assert!(!q.contains_key(&1));
+        q.check_invariants();
+    }
+
+    #[test]
+    fn weighted() {
+        let mut q = S3Fifo::with_weigher(10, 100, |_: &u32, v: &Vec<u8>| v.len());
+        q.insert(1, vec![0; 4]);
+        q.insert(2, vec![0; 4]);
+        assert_eq!(q.weight(), 8);
+        // Evicts both entries from the small queue to make room.
+        q.insert(3, vec![0; 9]);
+        assert_eq!(q.queue_keys(Queue::Small), vec![&3]);
+        assert_eq!(q.weight(), 9);
+
+        // Too big for the small queue, but fits in main.
+        q.insert(4, vec![0; 50]);
+        assert_eq!(q.queue_keys(Queue::Main), vec![&4]);
+
+        // Too big for anything.
+        let rejected = q.try_insert(5, vec![0; 101]).unwrap_err();
+        assert_eq!((rejected.key, rejected.weight), (5, 101));
+        assert_eq!(rejected.to_string(), "entry of weight 101 is larger than the cache");
+        assert_eq!(q.read(&5), None);
+        assert_eq!(q.try_insert(4, vec![0; 101]).unwrap_err().key, 4);
+        assert_eq!(q.read(&4).map(Vec::len), Some(50));
+        // insert removes the stale value instead.
+        assert_eq!(q.insert(4, vec![0; 101]).map(|v| v.len()), Some(50));
+        assert_eq!(q.read(&4), None);
+        q.check_invariants();
+
+        // Ghost hits go to main, which evicts until the new entry fits.
+        q.insert(1, vec![0; 60]);
+        q.insert(2, vec![0; 60]);
+        assert_eq!(q.queue_keys(Queue::Main), vec![&2]);
+        assert_eq!(q.weight(), 69);
+
+        // Growing a value in place evicts as needed too.
+        q.insert(6, vec![0; 1]);
+        q.read(&3);
+        q.read(&3);
+        assert_eq!(q.queue_keys(Queue::Small), vec![&6, &3]);
+        q.insert(6, vec![0; 2]);
+        assert_eq!(q.queue_keys(Queue::Small), vec![&6]);
+        assert_eq!(q.queue_keys(Queue::Main), vec![&3, &2]);
+        q.check_invariants();
+
+        // Growing a value evicts other entries, never the one being replaced.
+        let mut q = S3Fifo::with_weigher(10, 100, |_: &u32, v: &Vec<u8>| v.len());
+        q.insert(1, vec![0; 5]);
+        q.insert(2, vec![0; 5]);
+        assert_eq!(q.insert(1, vec![0; 8]).map(|v| v.len()), Some(5));
+        assert_eq!(q.queue_keys(Queue::Small), vec![&1]);
+        // And one which no longer fits in the small queue at all moves to main.
+        assert_eq!(q.insert(1, vec![0; 12]).map(|v| v.len()), Some(8));
+        assert_eq!(q.queue_keys(Queue::Small), Vec::<&u32>::new());
+        assert_eq!(q.queue_keys(Queue::Main), vec![&1]);
+        q.check_invariants();
+
+        match q.entry(7) {
+            entry::Entry::Vacant(e) => {
+                assert_eq!(e.try_insert(vec![0; 200]).unwrap_err().weight, 200);
+            }
+            entry::Entry::Occupied(_) => panic!("7 should not be cached"),
+        }
+        q.check_invariants();
+
+        // Replacing through an entry is weighed the same way as `insert`.
+        q.insert(2, vec![0; 5]);
+        let entry::Entry::Occupied(mut e) = q.entry(2) else {
+            panic!("2 should be cached");
+        };
+        assert_eq!(e.try_insert(vec![0; 500]).unwrap_err().weight, 500);
+        assert_eq!(e.get().len(), 5);
+        assert_eq!(e.insert(vec![0; 60]).len(), 5);
+        assert_eq!(q.queue_keys(Queue::Main), vec![&2, &1]);
+        let entry::Entry::Occupied(mut e) = q.entry(1) else {
+            panic!("1 should be cached");
+        };
+        e.insert(vec![0; 50]);
+        assert_eq!(q.queue_keys(Queue::Main), vec![&1]);
+        assert_eq!(q.weight(), 50);
+        q.check_invariants();
     }
 }
@@ -0,0 +1,44 @@
+use std::sync::Arc;
+
+/// Why an entry left the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum RemovalCause {
+    /// Evicted from the small queue without having been accessed enough to be promoted. Its key
+    /// is remembered in the ghost queue.
+    EvictedSmall,
+    /// Evicted from the main queue.
+    EvictedMain,
+    /// Its value was replaced by a new one for the same key.
+    Replaced,
+    /// Removed by `remove`, `purge`, `retain` or similar.
+    Removed,
+    /// Removed because it expired.
+    Expired,
+    /// Removed by `invalidate_all` or `clear`.
+    Cleared,
+}
+
+/// Gets told about every entry which leaves the cache, and why.
+///
+/// The listener runs after the cache has finished updating its queues for the removal, so if it
+/// panics, the cache is still consistent; the entry it was given is just dropped. It must not
+/// try to use the cache itself.
+///
+/// For `Replaced` and `Removed`, the value is handed back to the caller afterwards; otherwise it
+/// is dropped once the listener returns.
+pub trait EvictionListener<K, V> {
+    fn on_removal(&self, key: &K, value: &V, cause: RemovalCause);
+}
+
+impl<K, V, F: Fn(&K, &V, RemovalCause)> EvictionListener<K, V> for F {
+    fn on_removal(&self, key: &K, value: &V, cause: RemovalCause) {
+        self(key, value, cause)
+    }
+}
+
+impl<K, V, L: EvictionListener<K, V> + ?Sized> EvictionListener<K, V> for Arc<L> {
+    fn on_removal(&self, key: &K, value: &V, cause: RemovalCause) {
+        (**self).on_removal(key, value, cause)
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::time::Duration;
+
+use crate::clock::nanos;
+use crate::entry::Entry;
+use crate::S3Fifo;
+
+/// Loads values which aren't cached, for example from a database. See [`LoadingCache`].
+pub trait CacheLoader<K, V> {
+    type Error;
+
+    fn load(&self, key: &K) -> Result<V, Self::Error>;
+
+    /// Load several keys at once, returning one result for each key, in the same order. By
+    /// default, each is loaded on its own; implement this when a batch is cheaper.
+    fn load_all(&self, keys: &[K]) -> Vec<Result<V, Self::Error>> {
+        keys.iter().map(|key| self.load(key)).collect()
+    }
+}
+
+impl<K, V, E, F: Fn(&K) -> Result<V, E>> CacheLoader<K, V> for F {
+    type Error = E;
+
+    fn load(&self, key: &K) -> Result<V, E> {
+        self(key)
+    }
+}
+
+/// A read-through cache: `get` returns the cached value, or loads it with a [`CacheLoader`] and
+/// inserts it.
+///
+/// Failed loads aren't cached by default, so the next `get` tries again. With
+/// `set_error_caching`, the error is remembered for a while and returned without calling the
+/// loader, to spare a struggling backend. Errors are returned by cloning them, so a loader whose
+/// errors can't be cloned can wrap them in an `Arc`.
+pub struct LoadingCache<K, V, L: CacheLoader<K, V>> {
+    cache: S3Fifo<K, V>,
+    loader: L,
+    errors: Option<ErrorCache<K, L::Error>>,
+    // The last value loaded by `get` which couldn't be cached, so that it can still be returned.
+    uncached: Option<V>,
+}
+
+// Failed loads, with when to forget them on the cache's clock.
+struct ErrorCache<K, E> {
+    ttl: Duration,
+    errors: S3Fifo<K, (E, u64)>,
+}
+
+impl<K: Hash + Eq + Clone, E: Clone> ErrorCache<K, E> {
+    fn get(&mut self, key: &K, now: u64) -> Option<E> {
+        match self.errors.peek(key) {
+            Some((error, expires)) if *expires > now => Some(error.clone()),
+            Some(_) => {
+                self.errors.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, key: K, error: E, now: u64) {
+        self.errors.insert(key, (error, now.saturating_add(nanos(self.ttl))));
+    }
+}
+
+impl<K: Hash + Eq + Clone, V, L: CacheLoader<K, V>> LoadingCache<K, V, L> {
+    pub fn new(cache: S3Fifo<K, V>, loader: L) -> Self {
+        Self {
+            cache,
+            loader,
+            errors: None,
+            uncached: None,
+        }
+    }
+
+    /// Remember failed loads for `ttl` on the cache's clock, for up to `capacity` keys, failing
+    /// again with the same error rather than calling the loader. With `None`, failed loads are
+    /// forgotten straight away, which is the default.
+    pub fn set_error_caching(&mut self, ttl: Option<Duration>, capacity: usize) {
+        self.errors = ttl.map(|ttl| ErrorCache {
+            ttl,
+            errors: S3Fifo::builder()
+                .capacity(capacity)
+                .build()
+                .expect("default settings are valid"),
+        });
+    }
+
+    /// Remove a key from the cache, and forget any failure to load it.
+    pub fn invalidate(&mut self, key: &K) -> Option<V> {
+        if let Some(errors) = &mut self.errors {
+            errors.errors.remove(key);
+        }
+        self.cache.remove(key)
+    }
+
+    pub fn cache(&self) -> &S3Fifo<K, V> {
+        &self.cache
+    }
+
+    /// The underlying cache, to insert or remove values directly, change its settings, or read
+    /// its statistics.
+    pub fn cache_mut(&mut self) -> &mut S3Fifo<K, V> {
+        &mut self.cache
+    }
+
+    pub fn loader(&self) -> &L {
+        &self.loader
+    }
+
+    pub fn into_inner(self) -> (S3Fifo<K, V>, L) {
+        (self.cache, self.loader)
+    }
+}
+
+impl<K: Hash + Eq + Clone, V, L: CacheLoader<K, V>> LoadingCache<K, V, L>
+where
+    L::Error: Clone,
+{
+    /// Get the value of a key, loading and inserting it if it isn't cached.
+    ///
+    /// A loaded value which can't be inserted, because it's too heavy to ever fit in the cache or
+    /// pinned entries leave no room for it, is still returned, but it's loaded again next time.
+    pub fn get(&mut self, key: &K) -> Result<&V, L::Error> {
+        let now = self.cache.now();
+        self.uncached = None;
+        match self.cache.entry(key.clone()) {
+            Entry::Occupied(e) => Ok(e.into_mut()),
+            Entry::Vacant(e) => {
+                if let Some(error) = self.errors.as_mut().and_then(|errors| errors.get(key, now)) {
+                    return Err(error);
+                }
+                match self.loader.load(key) {
+                    Ok(value) => match e.try_insert(value) {
+                        Ok(value) => Ok(value),
+                        Err(rejected) => Ok(self.uncached.insert(rejected.value)),
+                    },
+                    Err(error) => {
+                        if let Some(errors) = &mut self.errors {
+                            errors.insert(key.clone(), error.clone(), now);
+                        }
+                        Err(error)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Get the values of several keys, loading all those which aren't cached with a single
+    /// `load_all`. The results are in the same order as the keys.
+    pub fn get_all(&mut self, keys: &[K]) -> Vec<Result<V, L::Error>>
+    where
+        V: Clone,
+    {
+        let now = self.cache.now();
+        let mut missing = vec![];
+        let mut seen = HashSet::new();
+        for key in keys {
+            let cached = self.cache.contains_key(key)
+                || self.errors.as_mut().is_some_and(|errors| errors.get(key, now).is_some());
+            if !cached && seen.insert(key) {
+                missing.push(key.clone());
+            }
+        }
+        let mut loaded = HashMap::new();
+        if !missing.is_empty() {
+            let results = self.loader.load_all(&missing);
+            let wrong_len = "load_all returned the wrong number of values";
+            assert_eq!(results.len(), missing.len(), "{wrong_len}");
+            for (key, result) in missing.into_iter().zip(results) {
+                match &result {
+                    Ok(value) => {
+                        self.cache.insert(key.clone(), value.clone());
+                    }
+                    Err(error) => {
+                        if let Some(errors) = &mut self.errors {
+                            errors.insert(key.clone(), error.clone(), now);
+                        }
+                    }
+                }
+                loaded.insert(key, result);
+            }
+        }
+        // Keys which were cached before the batch, but were evicted by it, are loaded again on
+        // their own here.
+        keys.iter()
+            .map(|key| match loaded.get(key) {
+                Some(result) => result.clone(),
+                None => self.get(key).cloned(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+    use std::cell::Cell;
+    use std::sync::Arc;
+
+    #[test]
+    fn loads_on_miss() {
+        let calls = Cell::new(0);
+        let loader = |key: &u32| {
+            calls.set(calls.get() + 1);
+            if *key < 100 {
+                Ok(key * 2)
+            } else {
+                Err(format!("no {key}"))
+            }
+        };
+        let mut cache = LoadingCache::new(S3Fifo::new(2, 8), loader);
+        assert_eq!(cache.get(&1), Ok(&2));
+        assert_eq!(cache.get(&1), Ok(&2));
+        assert_eq!(calls.get(), 1);
+        assert_eq!(cache.get(&100), Err("no 100".to_string()));
+        assert_eq!(cache.get(&100), Err("no 100".to_string()));
+        // Errors aren't cached by default.
+        assert_eq!(calls.get(), 3);
+        assert_eq!(cache.invalidate(&1), Some(2));
+        assert_eq!(cache.get(&1), Ok(&2));
+        assert_eq!(calls.get(), 4);
+    }
+
+    #[test]
+    fn caches_errors() {
+        let clock = Arc::new(ManualClock::new());
+        let calls = Cell::new(0);
+        let loader = |key: &u32| {
+            calls.set(calls.get() + 1);
+            Err::<u32, _>(*key)
+        };
+        let inner = S3Fifo::builder().capacity(10).clock(Arc::clone(&clock)).build().unwrap();
+        let mut cache = LoadingCache::new(inner, loader);
+        cache.set_error_caching(Some(Duration::from_secs(10)), 100);
+        assert_eq!(cache.get(&1), Err(1));
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(cache.get(&1), Err(1));
+        assert_eq!(calls.get(), 1);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(cache.get(&1), Err(1));
+        assert_eq!(calls.get(), 2);
+        cache.invalidate(&1);
+        assert_eq!(cache.get(&1), Err(1));
+        assert_eq!(calls.get(), 3);
+    }
+
+    // Records the keys of each batch, and fails to load 0.
+    struct Batched(Cell<Vec<Vec<u32>>>);
+
+    impl CacheLoader<u32, u32> for Batched {
+        type Error = ();
+
+        fn load(&self, key: &u32) -> Result<u32, ()> {
+            self.load_all(&[*key]).pop().unwrap()
+        }
+
+        fn load_all(&self, keys: &[u32]) -> Vec<Result<u32, ()>> {
+            let mut batches = self.0.take();
+            batches.push(keys.to_vec());
+            self.0.set(batches);
+            keys.iter().map(|&key| if key == 0 { Err(()) } else { Ok(key + 1) }).collect()
+        }
+    }
+
+    #[test]
+    fn batches() {
+        let mut cache = LoadingCache::new(S3Fifo::new(5, 5), Batched(Cell::new(vec![])));
+        cache.set_error_caching(Some(Duration::from_secs(1)), 10);
+        cache.get(&1).unwrap();
+        let values = cache.get_all(&[1, 2, 0, 3, 2]);
+        assert_eq!(values, [Ok(2), Ok(3), Err(()), Ok(4), Ok(3)]);
+        assert_eq!(cache.get_all(&[0, 3]), [Err(()), Ok(4)]);
+        assert_eq!(cache.loader().0.take(), [vec![1], vec![2, 0, 3]]);
+
+        // Without error caching, failures are still only loaded once per batch.
+        let mut cache = LoadingCache::new(S3Fifo::new(1, 1), Batched(Cell::new(vec![])));
+        assert_eq!(cache.get_all(&[0, 1, 2, 0]), [Err(()), Ok(2), Ok(3), Err(())]);
+        assert_eq!(cache.loader().0.take(), [vec![0, 1, 2]]);
+    }
+
+    #[test]
+    fn uncachable_values() {
+        let calls = Cell::new(0);
+        let loader = |key: &u32| {
+            calls.set(calls.get() + 1);
+            Ok::<_, ()>(vec![0; *key as usize])
+        };
+        let inner = S3Fifo::with_weigher(1, 10, |_: &u32, v: &Vec<u8>| v.len().max(1));
+        let mut cache = LoadingCache::new(inner, loader);
+        // Too heavy to cache, so it's loaded every time.
+        assert_eq!(cache.get(&20).map(Vec::len), Ok(20));
+        assert_eq!(cache.get(&20).map(Vec::len), Ok(20));
+        assert_eq!(calls.get(), 2);
+        assert!(!cache.cache().contains_key(&20));
+
+        // Nor one which only fits in the main queue, beside an entry pinned there.
+        assert_eq!(cache.get(&10).map(Vec::len), Ok(10));
+        assert!(cache.cache_mut().pin(&10));
+        assert_eq!(cache.get(&2).map(Vec::len), Ok(2));
+        assert_eq!(cache.get(&2).map(Vec::len), Ok(2));
+        assert_eq!(calls.get(), 5);
+        assert!(!cache.cache().contains_key(&2));
+    }
+}
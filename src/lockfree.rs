@@ -0,0 +1,836 @@
+//! A lock-free S3-FIFO cache, where the small, main and ghost queues are bounded ring buffers.
+
+use std::borrow::Borrow;
+use std::cell::UnsafeCell;
+use std::hash::{BuildHasher, Hash};
+use std::mem::MaybeUninit;
+use std::sync::atomic::Ordering::SeqCst;
+
+use crate::MAX_FREQ;
+
+mod ring;
+mod sync;
+mod table;
+
+use ring::RingBuffer;
+use sync::{bump, spin_loop, yield_now, AtomicU64, AtomicU8, AtomicUsize, RandomState};
+use table::Table;
+
+// Slot states. The low two bits are the lifecycle, the next bit marks an entry that was removed
+// from the index while still sitting in a queue, and the rest of the bits count readers.
+const FREE: u64 = 0;
+const LIVE: u64 = 0b10;
+const DEAD: u64 = 0b11;
+const LIFECYCLE: u64 = 0b11;
+const REMOVED: u64 = 0b100;
+const READER: u64 = 0b1000;
+
+struct Slot<K, V> {
+    state: AtomicU64,
+    freq: AtomicU8,
+    hash: AtomicU64,
+    // Insertion order, to decide which of two entries for the same key is the newer.
+    seq: AtomicU64,
+    data: UnsafeCell<MaybeUninit<(K, V)>>,
+}
+
+/// A concurrent S3-FIFO cache which never takes locks.
+///
+/// All entries live in a fixed array of `small + main` slots, and the small, main and ghost
+/// queues are bounded ring buffers of slot numbers, with the same capacities as in [`S3Fifo`].
+/// Slot numbers are found by key through a fixed-size hash table of atomic words.
+///
+/// Readers find a slot, register themselves on it, and clone the value (or look at it, with
+/// `read`) without ever waiting: a slot which is evicted while it's being read is only recycled
+/// once its last reader leaves. Inserts claim a free slot and push it onto a queue, running the
+/// S3-FIFO eviction steps on the queue tails when they're full, and so can proceed in parallel.
+///
+/// When used from a single thread, it makes the same decisions as [`S3Fifo`] does for inserts of
+/// keys which aren't cached. There are some differences, though:
+///
+/// - Re-inserting a cached key makes a new entry, which inherits the old one's access frequency,
+///   instead of replacing the value in place.
+/// - Removed entries keep their place in their queue until they reach its tail, but are no longer
+///   visible.
+/// - The ghost queue remembers 32-bit fingerprints of keys, so very rarely, an unrelated key may
+///   be mistaken for a recently evicted one.
+/// - There is no support for weighing entries: capacities are entry counts.
+///
+/// [`S3Fifo`]: crate::S3Fifo
+pub struct LockFreeS3Fifo<K, V> {
+    slots: Box<[Slot<K, V>]>,
+    free: RingBuffer,
+    small: RingBuffer,
+    main: RingBuffer,
+    ghost: RingBuffer,
+    // Words of the index are the upper half of a key's hash and its slot number plus one.
+    index: Table,
+    // Words of the ghost index are the upper half of a key's hash and a sequence number, so a
+    // stale ghost queue position can't remove a newer ghost entry for the same key.
+    ghost_index: Table,
+    hasher: RandomState,
+    len: AtomicUsize,
+    seq: AtomicU64,
+    ghost_seq: AtomicU64,
+}
+
+// Readers on any thread get references to keys and values, and whichever thread frees a slot
+// drops them.
+unsafe impl<K: Send + Sync, V: Send + Sync> Sync for LockFreeS3Fifo<K, V> {}
+
+/// A slot which can't be recycled until this is dropped.
+struct Pin<'a, K, V> {
+    cache: &'a LockFreeS3Fifo<K, V>,
+    idx: usize,
+}
+
+impl<K, V> Pin<'_, K, V> {
+    fn slot(&self) -> &Slot<K, V> {
+        &self.cache.slots[self.idx]
+    }
+
+    fn key(&self) -> &K {
+        // Safety: the slot is live, and being pinned, won't be freed.
+        unsafe { &(*self.slot().data.get()).assume_init_ref().0 }
+    }
+
+    fn value(&self) -> &V {
+        // Safety: as above.
+        unsafe { &(*self.slot().data.get()).assume_init_ref().1 }
+    }
+}
+
+impl<K, V> Drop for Pin<'_, K, V> {
+    fn drop(&mut self) {
+        let prev = self.slot().state.fetch_sub(READER, SeqCst);
+        if prev & LIFECYCLE == DEAD && prev / READER == 1 {
+            // The slot was given up while we were reading it, and we're the last reader out.
+            self.cache.free_slot(self.idx);
+        }
+    }
+}
+
+/// An entry found in the index: the pinned slot, and where its index word is.
+struct Found<'a, K, V> {
+    pin: Pin<'a, K, V>,
+    position: &'a AtomicU64,
+    word: u64,
+}
+
+impl<K: Hash + Eq, V> LockFreeS3Fifo<K, V> {
+    /// Create a cache holding up to `small` entries in the small queue and `main` entries in the
+    /// main queue. The ghost queue remembers up to `main` keys.
+    pub fn new(small: usize, main: usize) -> Self {
+        let total = small + main;
+        assert!(total < u32::MAX as usize, "too many entries for a lock-free cache");
+        let free = RingBuffer::new(total);
+        for idx in 0 .. total {
+            free.push(idx as u64).unwrap();
+        }
+        Self {
+            slots: (0 .. total)
+                .map(|_| Slot {
+                    state: AtomicU64::new(FREE),
+                    freq: AtomicU8::new(0),
+                    hash: AtomicU64::new(0),
+                    seq: AtomicU64::new(0),
+                    data: UnsafeCell::new(MaybeUninit::uninit()),
+                })
+                .collect(),
+            free,
+            small: RingBuffer::new(small),
+            main: RingBuffer::new(main),
+            ghost: RingBuffer::new(main),
+            index: Table::new(total),
+            ghost_index: Table::new(main),
+            hasher: RandomState::default(),
+            len: AtomicUsize::new(0),
+            seq: AtomicU64::new(0),
+            ghost_seq: AtomicU64::new(0),
+        }
+    }
+
+    /// Insert a value, superseding any cached value for the key.
+    ///
+    /// Returns false in the unlikely case that the index has no room left for the key, in which
+    /// case the value is dropped without being cached. Also returns false for every insert if the
+    /// main queue has no capacity, since then no entry fits in it, like in `S3Fifo`.
+    pub fn insert(&self, key: K, value: V) -> bool {
+        if self.main.capacity() == 0 {
+            return false;
+        }
+        let hash = self.hasher.hash_one(&key);
+        // Entries that can't fit in the small queue at all go straight to main too.
+        let main = self.ghost_remove(hash) || self.small.capacity() == 0;
+        let idx = self.alloc(main);
+        let slot = &self.slots[idx];
+        // Safety: we took the slot off the free list, so nobody else is looking at it.
+        unsafe { (*slot.data.get()).write((key, value)) };
+        slot.hash.store(hash, SeqCst);
+        slot.seq.store(self.seq.fetch_add(1, SeqCst), SeqCst);
+        slot.freq.store(0, SeqCst);
+        slot.state.store(LIVE, SeqCst);
+
+        let word = index_word(hash, idx);
+        if !self.index.insert(hash, word) {
+            self.reclaim(idx);
+            return false;
+        }
+        self.len.fetch_add(1, SeqCst);
+        self.dedup(idx, hash, word);
+        if slot.state.load(SeqCst) & REMOVED != 0 {
+            // Already superseded or removed, so don't bother queueing it.
+            self.reclaim(idx);
+        } else if main {
+            self.push_main(idx);
+        } else {
+            while self.small.push(idx as u64).is_err() {
+                if !self.evict_small() {
+                    yield_now();
+                }
+            }
+        }
+        true
+    }
+
+    /// Look up a key and return a clone of its value.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.read(key, V::clone)
+    }
+
+    /// Look up a key and call a function with a reference to its value, returning the result.
+    ///
+    /// The entry can be evicted in the meantime, but its memory is not reused until the
+    /// function returns.
+    pub fn read<Q, R>(&self, key: &Q, f: impl FnOnce(&V) -> R) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let found = self.find(key, self.hasher.hash_one(key))?;
+        bump(&found.pin.slot().freq, MAX_FREQ);
+        Some(f(found.pin.value()))
+    }
+
+    /// Remove a key, returning whether it was cached.
+    pub fn remove<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key);
+        // Insertion order of the entry we removed.
+        let mut removed: Option<u64> = None;
+        // An entry we fail to unindex was superseded by a concurrent insert, or evicted, so look
+        // again. After removing one, also take out any older entries, which an insert that hasn't
+        // deduplicated yet leaves indexed behind it, but not newer ones, which were inserted
+        // after us.
+        while let Some(found) = self.find(key, hash) {
+            let seq = found.pin.slot().seq.load(SeqCst);
+            if removed.is_some_and(|removed| seq > removed) {
+                break;
+            }
+            if self.unindex(&found.pin, found.position, found.word) {
+                removed.get_or_insert(seq);
+            }
+        }
+        removed.is_some()
+    }
+
+    /// Number of cached entries. This is only a snapshot when other threads are using the cache.
+    pub fn len(&self) -> usize {
+        self.len.load(SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn pin(&self, idx: usize) -> Option<Pin<'_, K, V>> {
+        let state = &self.slots[idx].state;
+        let mut current = state.load(SeqCst);
+        loop {
+            if current & LIFECYCLE != LIVE {
+                return None;
+            }
+            match state.compare_exchange_weak(current, current + READER, SeqCst, SeqCst) {
+                Ok(_) => return Some(Pin { cache: self, idx }),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Find the newest indexed entry for a key.
+    fn find<Q>(&self, key: &Q, hash: u64) -> Option<Found<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        loop {
+            // The words for the key's fingerprint, as we saw them.
+            let mut seen = [0; table::CANDIDATES];
+            if let Some(found) = self.find_once(key, hash, &mut seen) {
+                return Some(found);
+            }
+            // An insert can put the key in a position we had already passed, and its `dedup`
+            // then take the old entry out of one we hadn't reached yet, so a miss only counts if
+            // none of the positions changed in the meantime.
+            let unchanged = self.index.candidates(hash).zip(seen).all(|(position, seen)| {
+                let word = position.load(SeqCst);
+                word == seen || (seen == 0 && word >> 32 != hash >> 32)
+            });
+            if unchanged {
+                return None;
+            }
+        }
+    }
+
+    fn find_once<Q>(&self, key: &Q, hash: u64, seen: &mut [u64]) -> Option<Found<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut best: Option<(Found<'_, K, V>, u64)> = None;
+        for (position, seen) in self.index.candidates(hash).zip(seen) {
+            let word = position.load(SeqCst);
+            if word == 0 || word >> 32 != hash >> 32 {
+                continue;
+            }
+            *seen = word;
+            let Some(pin) = self.pin(word_slot(word)) else {
+                continue;
+            };
+            // Make sure the slot wasn't recycled for another entry before we pinned it.
+            if pin.key().borrow() != key || position.load(SeqCst) != word {
+                continue;
+            }
+            let seq = pin.slot().seq.load(SeqCst);
+            if best.as_ref().is_none_or(|(_, best_seq)| seq > *best_seq) {
+                best = Some((Found { pin, position, word }, seq));
+            }
+        }
+        best.map(|(found, _)| found)
+    }
+
+    /// Take a pinned entry out of the index. Returns false if someone else already did.
+    fn unindex(&self, pin: &Pin<'_, K, V>, position: &AtomicU64, word: u64) -> bool {
+        if position.compare_exchange(word, 0, SeqCst, SeqCst).is_ok() {
+            self.len.fetch_sub(1, SeqCst);
+            pin.slot().state.fetch_or(REMOVED, SeqCst);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take an entry which was just popped off a queue out of the index, if it's still there.
+    fn unindex_owned(&self, idx: usize) {
+        let hash = self.slots[idx].hash.load(SeqCst);
+        if self.index.remove(hash, index_word(hash, idx)) {
+            self.len.fetch_sub(1, SeqCst);
+        }
+    }
+
+    /// Resolve concurrent inserts of the same key, so that only the newest entry stays indexed.
+    ///
+    /// Each inserter checks for other entries of the same key after indexing its own, and
+    /// whichever sees both removes the older one. The later of the two to be indexed always sees
+    /// the earlier one, so the older entry is always removed.
+    fn dedup(&self, idx: usize, hash: u64, word: u64) {
+        let Some(mine) = self.pin(idx) else {
+            return;
+        };
+        let my_seq = mine.slot().seq.load(SeqCst);
+        for position in self.index.candidates(hash) {
+            let other_word = position.load(SeqCst);
+            if other_word == 0 || other_word == word || other_word >> 32 != hash >> 32 {
+                continue;
+            }
+            let Some(other) = self.pin(word_slot(other_word)) else {
+                continue;
+            };
+            if other.key() != mine.key() {
+                continue;
+            }
+            if other.slot().seq.load(SeqCst) < my_seq {
+                // Take over the access count of the entry being superseded.
+                mine.slot().freq.fetch_max(other.slot().freq.load(SeqCst), SeqCst);
+                self.unindex(&other, position, other_word);
+            } else {
+                // We're the older one.
+                let mut positions = self.index.candidates(hash);
+                if let Some(position) = positions.find(|p| p.load(SeqCst) == word) {
+                    self.unindex(&mine, position, word);
+                }
+            }
+        }
+    }
+
+    /// Get a free slot, evicting an entry if there isn't one.
+    fn alloc(&self, main: bool) -> usize {
+        loop {
+            if let Some(idx) = self.free.pop() {
+                return idx as usize;
+            }
+            // Every slot is in use, so the queue we're inserting into is full: evict from it,
+            // like S3Fifo would before inserting.
+            let evicted = if main {
+                self.evict_main()
+            } else {
+                self.evict_small() || self.evict_main()
+            };
+            if !evicted {
+                // All the slots are held by inserts in progress.
+                yield_now();
+            }
+        }
+    }
+
+    fn push_main(&self, idx: usize) {
+        while self.main.push(idx as u64).is_err() {
+            if !self.evict_main() {
+                yield_now();
+            }
+        }
+    }
+
+    /// Evict the entry at the tail of the small queue. Returns false if the queue is empty.
+    fn evict_small(&self) -> bool {
+        let Some(idx) = self.small.pop() else {
+            return false;
+        };
+        let idx = idx as usize;
+        let slot = &self.slots[idx];
+        if slot.state.load(SeqCst) & REMOVED != 0 {
+            self.reclaim(idx);
+        } else if slot.freq.load(SeqCst) > 1 {
+            self.push_main(idx);
+        } else {
+            self.unindex_owned(idx);
+            self.ghost_insert(slot.hash.load(SeqCst));
+            self.reclaim(idx);
+        }
+        true
+    }
+
+    /// Evict an entry from the main queue, giving entries at the tail another lap for every
+    /// access they had. Returns false if the queue is empty.
+    fn evict_main(&self) -> bool {
+        loop {
+            let Some(idx) = self.main.pop() else {
+                return false;
+            };
+            let idx = idx as usize;
+            let slot = &self.slots[idx];
+            if slot.state.load(SeqCst) & REMOVED == 0 {
+                let n = slot.freq.load(SeqCst);
+                if n > 0 {
+                    slot.freq.store(n - 1, SeqCst);
+                    if self.main.push(idx as u64).is_ok() {
+                        continue;
+                    }
+                    // Other inserts took the space we just made; evict this one after all rather
+                    // than wait for them.
+                }
+                self.unindex_owned(idx);
+            }
+            self.reclaim(idx);
+            return true;
+        }
+    }
+
+    /// Give up a slot which was taken off a queue (or never made it onto one), and which is no
+    /// longer indexed. It is freed as soon as any readers are done with it.
+    fn reclaim(&self, idx: usize) {
+        let prev = self.slots[idx].state.fetch_or(DEAD, SeqCst);
+        if prev / READER == 0 {
+            self.free_slot(idx);
+        }
+    }
+
+    fn ghost_insert(&self, hash: u64) {
+        if self.ghost.capacity() == 0 {
+            return;
+        }
+        // Like S3Fifo's ghost queue, only remember the latest eviction of a key.
+        self.ghost_remove(hash);
+        let seq = self.ghost_seq.fetch_add(1, SeqCst) % u64::from(u32::MAX) + 1;
+        let word = (hash & !0xffff_ffff) | seq;
+        while self.ghost.push(word).is_err() {
+            if let Some(old) = self.ghost.pop() {
+                self.ghost_index.remove(old >> 32, old);
+            }
+        }
+        self.ghost_index.insert(word >> 32, word);
+    }
+
+    fn ghost_remove(&self, hash: u64) -> bool {
+        let fingerprint = hash >> 32;
+        self.ghost_index.candidates(fingerprint).any(|position| {
+            let word = position.load(SeqCst);
+            word != 0
+                && word >> 32 == fingerprint
+                && position.compare_exchange(word, 0, SeqCst, SeqCst).is_ok()
+        })
+    }
+}
+
+impl<K, V> LockFreeS3Fifo<K, V> {
+    fn free_slot(&self, idx: usize) {
+        let slot = &self.slots[idx];
+        // Safety: the slot is dead and has no readers, so nothing else can be looking at it.
+        unsafe { (*slot.data.get()).assume_init_drop() };
+        slot.state.store(FREE, SeqCst);
+        // There is always room for every slot on the free list, but a push can still fail while
+        // another thread is halfway through popping the value in the way.
+        while self.free.push(idx as u64).is_err() {
+            spin_loop();
+        }
+    }
+}
+
+impl<K, V> Drop for LockFreeS3Fifo<K, V> {
+    fn drop(&mut self) {
+        for slot in self.slots.iter_mut() {
+            if slot.state.load(SeqCst) & LIFECYCLE != FREE {
+                // Safety: nobody else can be using the cache anymore.
+                unsafe { slot.data.get_mut().assume_init_drop() };
+            }
+        }
+    }
+}
+
+fn index_word(hash: u64, idx: usize) -> u64 {
+    (hash & !0xffff_ffff) | (idx as u64 + 1)
+}
+
+fn word_slot(word: u64) -> usize {
+    (word & 0xffff_ffff) as usize - 1
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use crate::S3Fifo;
+    use rand::{Rng, SeedableRng};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+
+    /// Skewed keys, so that there's a mix of hits, ghost hits and promotions.
+    fn skewed_key(rng: &mut impl Rng) -> u32 {
+        let x: f64 = rng.gen();
+        (x * x * x * 200.) as u32
+    }
+
+    #[test]
+    fn matches_s3fifo() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut seq = S3Fifo::<u32, u32>::new(5, 45);
+        let lf = LockFreeS3Fifo::<u32, u32>::new(5, 45);
+        let mut hits = 0;
+        for _ in 0 .. 50_000 {
+            let k = skewed_key(&mut rng);
+            let expected = seq.read(&k).copied();
+            assert_eq!(lf.get(&k), expected);
+            if expected.is_some() {
+                hits += 1;
+            } else {
+                seq.insert(k, k);
+                assert!(lf.insert(k, k));
+            }
+            assert_eq!(lf.len(), seq.len());
+        }
+        assert!(hits > 10_000, "{hits}");
+    }
+
+    #[test]
+    fn replace_and_remove() {
+        let q = LockFreeS3Fifo::<u32, &str>::new(2, 4);
+        assert!(q.insert(1, "a"));
+        assert_eq!(q.get(&1), Some("a"));
+        assert_eq!(q.get(&1), Some("a"));
+        assert!(q.insert(1, "b"));
+        assert_eq!(q.get(&1), Some("b"));
+        assert_eq!(q.len(), 1);
+        // The new entry took over the access count, so it gets promoted.
+        assert_eq!(q.read(&1, |_| ()), Some(()));
+        assert!(q.insert(2, "x"));
+        assert!(q.insert(3, "x"));
+        assert!(q.insert(4, "x"));
+        assert_eq!(q.get(&1), Some("b"));
+        assert_eq!(q.get(&2), None);
+
+        assert!(q.remove(&1));
+        assert!(!q.remove(&1));
+        assert_eq!(q.get(&1), None);
+        assert_eq!(q.len(), 2);
+        for k in 5 .. 20 {
+            q.insert(k, "y");
+        }
+        assert_eq!(q.len(), 2);
+
+        // Without a main queue, nothing fits, as in S3Fifo.
+        for (small, main) in [(0, 0), (2, 0)] {
+            let q = LockFreeS3Fifo::<u32, &str>::new(small, main);
+            assert!(!q.insert(1, "a"));
+            assert_eq!((q.get(&1), q.len()), (None, 0));
+        }
+    }
+
+    #[test]
+    fn drops_values() {
+        let value = Arc::new(());
+        {
+            let q = LockFreeS3Fifo::new(3, 10);
+            std::thread::scope(|s| {
+                for t in 0 .. 4 {
+                    let (q, value) = (&q, &value);
+                    s.spawn(move || {
+                        let mut rng = rand::rngs::StdRng::seed_from_u64(t);
+                        for _ in 0 .. 10_000 {
+                            let k = rng.gen_range(0..30);
+                            if rng.gen_bool(0.1) {
+                                q.remove(&k);
+                            } else if q.read(&k, |_| ()).is_none() {
+                                q.insert(k, Arc::clone(value));
+                            }
+                        }
+                    });
+                }
+            });
+            assert!(q.len() <= 13);
+            assert!(Arc::strong_count(&value) <= 14);
+        }
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    enum Op {
+        Insert(u64),
+        Get(Option<u64>),
+        Remove(bool),
+    }
+
+    #[derive(Debug)]
+    struct Call {
+        start: u64,
+        end: u64,
+        op: Op,
+    }
+
+    /// Check that a history of operations on one key can be explained by them happening one at a
+    /// time, each at some point between its start and end, on a sequential cache. If `may_evict`,
+    /// that cache may drop the key at any time; otherwise, it's what `S3Fifo` does when it never
+    /// has to evict anything, so the key is cached from an insert until a remove.
+    fn linearizable(calls: &[Call], may_evict: bool) -> bool {
+        fn search(
+            calls: &[Call],
+            may_evict: bool,
+            done: u64,
+            state: Option<u64>,
+            seen: &mut HashSet<(u64, Option<u64>)>,
+        ) -> bool {
+            if done.count_ones() as usize == calls.len() {
+                return true;
+            }
+            if !seen.insert((done, state)) {
+                return false;
+            }
+            let pending = || (0 .. calls.len()).filter(|i| done & (1 << i) == 0);
+            let deadline = pending().map(|i| calls[i].end).min().unwrap();
+            for i in pending().filter(|&i| calls[i].start < deadline) {
+                let next = match calls[i].op {
+                    Op::Insert(v) => Some(Some(v)),
+                    // A miss or a failed removal may be an eviction.
+                    Op::Get(None) | Op::Remove(false) => {
+                        (may_evict || state.is_none()).then_some(None)
+                    }
+                    Op::Get(Some(v)) => (state == Some(v)).then_some(state),
+                    Op::Remove(true) => state.is_some().then_some(None),
+                };
+                if let Some(next) = next {
+                    if search(calls, may_evict, done | (1 << i), next, seen) {
+                        return true;
+                    }
+                }
+            }
+            false
+        }
+        search(calls, may_evict, 0, None, &mut HashSet::new())
+    }
+
+    #[test]
+    fn linearizability() {
+        let clock = AtomicU64::new(0);
+        let next_value = AtomicU64::new(0);
+        // A tiny cache which evicts all the time, and one with a slot for every insert of a
+        // round, which never does.
+        let caches = [(1, 2, true), (18, 18, false)];
+        for round in 0 .. 4000 {
+            let (small, main, may_evict) = caches[(round % 2) as usize];
+            let q = LockFreeS3Fifo::<u32, u64>::new(small, main);
+            let histories: Vec<Vec<(u32, Call)>> = std::thread::scope(|s| {
+                let threads: Vec<_> = (0 .. 3)
+                    .map(|t| {
+                        let (q, clock, next_value) = (&q, &clock, &next_value);
+                        s.spawn(move || {
+                            let mut rng = rand::rngs::StdRng::seed_from_u64(round * 3 + t);
+                            (0 .. 6)
+                                .map(|_| {
+                                    let k = rng.gen_range(0..3);
+                                    let start = clock.fetch_add(1, SeqCst);
+                                    let op = match rng.gen_range(0..5) {
+                                        0 ..= 1 => {
+                                            let v = next_value.fetch_add(1, SeqCst);
+                                            assert!(q.insert(k, v) || may_evict);
+                                            Op::Insert(v)
+                                        }
+                                        2 => Op::Remove(q.remove(&k)),
+                                        _ => Op::Get(q.get(&k)),
+                                    };
+                                    let end = clock.fetch_add(1, SeqCst);
+                                    (k, Call { start, end, op })
+                                })
+                                .collect()
+                        })
+                    })
+                    .collect();
+                threads.into_iter().map(|t| t.join().unwrap()).collect()
+            });
+            let mut by_key: HashMap<u32, Vec<Call>> = HashMap::new();
+            for (k, call) in histories.into_iter().flatten() {
+                by_key.entry(k).or_default().push(call);
+            }
+            for calls in by_key.values() {
+                assert!(linearizable(calls, may_evict), "{calls:#?}");
+            }
+        }
+    }
+
+    #[test]
+    fn threads() {
+        let q = LockFreeS3Fifo::<u32, u32>::new(10, 90);
+        std::thread::scope(|s| {
+            for t in 0 .. 8 {
+                let q = &q;
+                s.spawn(move || {
+                    let mut rng = rand::rngs::StdRng::seed_from_u64(t);
+                    for _ in 0 .. 20_000 {
+                        let k = skewed_key(&mut rng);
+                        match q.get(&k) {
+                            Some(v) => assert_eq!(v, k * 2),
+                            None => {
+                                q.insert(k, k * 2);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        assert!(q.len() <= 100);
+        let free = q.free.len();
+        assert_eq!(free + q.small.len() + q.main.len(), 100);
+    }
+}
+
+/// Model checks of the races between readers, inserts, removals and eviction, run with
+/// `RUSTFLAGS="--cfg loom" cargo test --release lockfree`.
+#[cfg(all(test, loom))]
+mod models {
+    use super::*;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    fn model(f: impl Fn() + Sync + Send + 'static) {
+        let mut builder = loom::model::Builder::new();
+        // Every interleaving of whole inserts is far too many to explore, but the races here
+        // only take a couple of preemptions. `LOOM_MAX_PREEMPTIONS` overrides this.
+        builder.preemption_bound.get_or_insert(2);
+        builder.max_branches = 100_000;
+        builder.check(f);
+    }
+
+    /// A value which counts its copies, and is zeroed when dropped, so that reading one after its
+    /// slot was freed shows.
+    struct Value {
+        n: u32,
+        _copies: std::sync::Arc<()>,
+    }
+
+    impl Drop for Value {
+        fn drop(&mut self) {
+            // Volatile, since the value is dead afterwards, and the write could otherwise go.
+            unsafe { std::ptr::write_volatile(&mut self.n, 0) };
+        }
+    }
+
+    /// An insert evicts an entry while it's being read, so the slot is freed by whichever of them
+    /// is done with it last, and not before.
+    #[test]
+    fn evict_while_reading() {
+        model(|| {
+            let copies = std::sync::Arc::new(());
+            let value = |n| Value {
+                n,
+                _copies: std::sync::Arc::clone(&copies),
+            };
+            let q = Arc::new(LockFreeS3Fifo::new(1, 1));
+            assert!(q.insert(1, value(10)));
+            let reader = {
+                let q = Arc::clone(&q);
+                thread::spawn(move || q.read(&1, |v| v.n))
+            };
+            assert!(q.insert(2, value(20)));
+            assert!(matches!(reader.join().unwrap(), None | Some(10)));
+            assert_eq!((q.read(&1, |v| v.n), q.len()), (None, 1));
+            assert_eq!(std::sync::Arc::strong_count(&copies), 2);
+            assert_eq!(q.free.len() + q.small.len() + q.main.len(), 2);
+            drop(q);
+            assert_eq!(std::sync::Arc::strong_count(&copies), 1);
+        });
+    }
+
+    /// A removal racing with an insert of the same key, which is cached throughout, removes one
+    /// of the two values.
+    #[test]
+    fn remove_while_inserting() {
+        model(|| {
+            let q = Arc::new(LockFreeS3Fifo::new(2, 2));
+            assert!(q.insert(1, 10));
+            let remover = {
+                let q = Arc::clone(&q);
+                thread::spawn(move || q.remove(&1))
+            };
+            assert!(q.insert(1, 11));
+            assert!(remover.join().unwrap());
+            let left = q.get(&1);
+            // The insert either came first and was removed, or came last and stays.
+            assert!(matches!(left, None | Some(11)), "{left:?}");
+            assert_eq!(q.len(), usize::from(left.is_some()));
+        });
+    }
+
+    /// A lookup racing with an insert of the same key sees one of the two values, and never
+    /// misses, even when the new entry is indexed in a position the lookup has already passed.
+    #[test]
+    fn get_while_inserting() {
+        model(|| {
+            // The index has a single bucket, and inserting 1 evicts 2, leaving a gap before 1.
+            let q = Arc::new(LockFreeS3Fifo::new(1, 1));
+            assert!(q.insert(2, 20));
+            assert!(q.insert(1, 10));
+            let reader = {
+                let q = Arc::clone(&q);
+                thread::spawn(move || q.get(&1))
+            };
+            assert!(q.insert(1, 11));
+            assert!(matches!(reader.join().unwrap(), Some(10 | 11)));
+            assert_eq!((q.get(&1), q.len()), (Some(11), 1));
+        });
+    }
+}
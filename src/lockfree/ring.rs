@@ -0,0 +1,181 @@
+use std::sync::atomic::Ordering::SeqCst;
+
+use super::sync::{AtomicU64, AtomicUsize};
+
+/// A bounded multi-producer multi-consumer FIFO queue of `u64`s, after Dmitry Vyukov's design.
+///
+/// Each cell carries a sequence number which tells producers and consumers whether it is their
+/// turn to use it, so claiming a position is a single compare-and-swap on the shared head or tail
+/// counter. Unlike the original, the capacity doesn't need to be a power of two, so that it can
+/// match a queue size exactly. That includes a capacity of one, which is why sequence numbers
+/// are twice the position, plus one once the cell is written: otherwise a written cell would look
+/// ready for the next write.
+pub(crate) struct RingBuffer {
+    cells: Box<[Cell]>,
+    enqueue_pos: AtomicUsize,
+    dequeue_pos: AtomicUsize,
+}
+
+struct Cell {
+    seq: AtomicUsize,
+    value: AtomicU64,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            cells: (0 .. capacity)
+                .map(|i| Cell {
+                    seq: AtomicUsize::new(2 * i),
+                    value: AtomicU64::new(0),
+                })
+                .collect(),
+            enqueue_pos: AtomicUsize::new(0),
+            dequeue_pos: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Number of values in the queue. This is only a snapshot when other threads are using it.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        let dequeue = self.dequeue_pos.load(SeqCst);
+        let enqueue = self.enqueue_pos.load(SeqCst);
+        enqueue.saturating_sub(dequeue)
+    }
+
+    /// Add a value on the head of the queue, or give it back if the queue is full.
+    ///
+    /// This can also fail if the queue was full until just now, and the thread which popped a
+    /// value from it hasn't quite finished.
+    pub fn push(&self, value: u64) -> Result<(), u64> {
+        if self.cells.is_empty() {
+            return Err(value);
+        }
+        let mut pos = self.enqueue_pos.load(SeqCst);
+        loop {
+            let cell = &self.cells[pos % self.cells.len()];
+            let seq = cell.seq.load(SeqCst);
+            if seq == 2 * pos {
+                match self.enqueue_pos.compare_exchange_weak(pos, pos + 1, SeqCst, SeqCst) {
+                    Ok(_) => {
+                        cell.value.store(value, SeqCst);
+                        cell.seq.store(2 * pos + 1, SeqCst);
+                        return Ok(());
+                    }
+                    Err(actual) => pos = actual,
+                }
+            } else if seq < 2 * pos {
+                // The cell still holds the value from a lap ago: we're full.
+                return Err(value);
+            } else {
+                pos = self.enqueue_pos.load(SeqCst);
+            }
+        }
+    }
+
+    /// Take the value at the tail of the queue.
+    pub fn pop(&self) -> Option<u64> {
+        if self.cells.is_empty() {
+            return None;
+        }
+        let mut pos = self.dequeue_pos.load(SeqCst);
+        loop {
+            let cell = &self.cells[pos % self.cells.len()];
+            let seq = cell.seq.load(SeqCst);
+            if seq == 2 * pos + 1 {
+                match self.dequeue_pos.compare_exchange_weak(pos, pos + 1, SeqCst, SeqCst) {
+                    Ok(_) => {
+                        let value = cell.value.load(SeqCst);
+                        cell.seq.store(2 * (pos + self.cells.len()), SeqCst);
+                        return Some(value);
+                    }
+                    Err(actual) => pos = actual,
+                }
+            } else if seq < 2 * pos + 1 {
+                // Nothing has been written here since the last lap: we're empty.
+                return None;
+            } else {
+                pos = self.dequeue_pos.load(SeqCst);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fifo() {
+        let q = RingBuffer::new(3);
+        assert_eq!(q.pop(), None);
+        for round in 0 .. 5 {
+            assert_eq!(q.push(round), Ok(()));
+            assert_eq!(q.push(round + 1), Ok(()));
+            assert_eq!(q.push(round + 2), Ok(()));
+            assert_eq!(q.push(99), Err(99));
+            assert_eq!(q.len(), 3);
+            assert_eq!(q.pop(), Some(round));
+            assert_eq!(q.pop(), Some(round + 1));
+            assert_eq!(q.pop(), Some(round + 2));
+            assert_eq!(q.pop(), None);
+        }
+        assert_eq!(RingBuffer::new(0).push(1), Err(1));
+
+        let q = RingBuffer::new(1);
+        assert_eq!(q.push(1), Ok(()));
+        assert_eq!(q.push(2), Err(2));
+        assert_eq!(q.pop(), Some(1));
+        assert_eq!(q.push(3), Ok(()));
+        assert_eq!(q.pop(), Some(3));
+        assert_eq!(q.pop(), None);
+    }
+
+    #[test]
+    fn threads() {
+        let q = RingBuffer::new(7);
+        let total = AtomicU64::new(0);
+        std::thread::scope(|s| {
+            for t in 0 .. 4 {
+                let q = &q;
+                s.spawn(move || {
+                    for i in 0 .. 10_000 {
+                        let mut v = t * 100_000 + i;
+                        while let Err(back) = q.push(v) {
+                            v = back;
+                            std::thread::yield_now();
+                        }
+                    }
+                });
+            }
+            for _ in 0 .. 4 {
+                let (q, total) = (&q, &total);
+                s.spawn(move || {
+                    let mut last = [None; 4];
+                    let mut n = 0;
+                    while n < 10_000 {
+                        if let Some(v) = q.pop() {
+                            // Values from a given producer come out in order.
+                            let (t, i) = ((v / 100_000) as usize, v % 100_000);
+                            assert!(last[t] < Some(i));
+                            last[t] = Some(i);
+                            total.fetch_add(v, SeqCst);
+                            n += 1;
+                        } else {
+                            std::thread::yield_now();
+                        }
+                    }
+                });
+            }
+        });
+        let expected: u64 = (0 .. 4)
+            .map(|t| (0 .. 10_000).map(|i| t * 100_000 + i).sum::<u64>())
+            .sum();
+        assert_eq!(total.load(SeqCst), expected);
+        assert_eq!(q.pop(), None);
+    }
+}
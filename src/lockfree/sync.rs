@@ -0,0 +1,37 @@
+//! The atomics and spinning primitives of the lock-free cache, which are loom's when model
+//! checking with `--cfg loom`.
+
+#[cfg(not(loom))]
+pub(crate) use std::collections::hash_map::RandomState;
+#[cfg(not(loom))]
+pub(crate) use std::hint::spin_loop;
+#[cfg(not(loom))]
+pub(crate) use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize};
+#[cfg(not(loom))]
+pub(crate) use std::thread::yield_now;
+
+#[cfg(not(loom))]
+pub(crate) use crate::bump;
+
+#[cfg(loom)]
+pub(crate) use loom::hint::spin_loop;
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize};
+#[cfg(loom)]
+pub(crate) use loom::thread::yield_now;
+
+/// Loom explores one execution at a time and expects them to repeat, so keys must land in the
+/// same buckets every time.
+#[cfg(loom)]
+pub(crate) type RandomState =
+    std::hash::BuildHasherDefault<std::collections::hash_map::DefaultHasher>;
+
+/// `crate::bump`, for loom's `AtomicU8`.
+#[cfg(loom)]
+pub(crate) fn bump(freq: &AtomicU8, max: u8) {
+    use std::sync::atomic::Ordering::SeqCst;
+
+    if freq.fetch_add(1, SeqCst) + 1 > max {
+        freq.store(max, SeqCst);
+    }
+}
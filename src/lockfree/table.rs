@@ -0,0 +1,98 @@
+use std::sync::atomic::Ordering::SeqCst;
+
+use super::sync::AtomicU64;
+
+// Words per bucket; 8 of them fill a cache line.
+const BUCKET: usize = 8;
+
+/// The most positions `Table::candidates` yields.
+pub(crate) const CANDIDATES: usize = 2 * BUCKET;
+
+/// A fixed-size hash set of non-zero `u64` words, where zero marks an empty position.
+///
+/// Every word lives in one of two buckets picked by a hash, and is inserted into whichever of
+/// them is emptier. Words never move once inserted, so a lookup only needs to scan two buckets,
+/// and removal is just swapping a word back to zero. With two choices, buckets stay far below
+/// their capacity as long as the set holds at most twice as many words as there are buckets,
+/// which is how it is sized.
+pub(crate) struct Table {
+    words: Box<[AtomicU64]>,
+    mask: usize,
+}
+
+impl Table {
+    /// Create a table which can comfortably hold up to `capacity` words.
+    pub fn new(capacity: usize) -> Self {
+        let buckets = capacity.div_ceil(2).max(1).next_power_of_two();
+        Self {
+            words: (0 .. buckets * BUCKET).map(|_| AtomicU64::new(0)).collect(),
+            mask: buckets - 1,
+        }
+    }
+
+    fn buckets(&self, hash: u64) -> [&[AtomicU64]; 2] {
+        let first = hash as usize & self.mask;
+        let second = (hash.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize & self.mask;
+        [
+            &self.words[first * BUCKET .. (first + 1) * BUCKET],
+            &self.words[second * BUCKET .. (second + 1) * BUCKET],
+        ]
+    }
+
+    /// Insert a word in one of the buckets for the hash. Returns false if both are full.
+    pub fn insert(&self, hash: u64, word: u64) -> bool {
+        debug_assert_ne!(word, 0);
+        let [first, second] = self.buckets(hash);
+        let used = |bucket: &[AtomicU64]| bucket.iter().filter(|w| w.load(SeqCst) != 0).count();
+        let (a, b) = if used(second) < used(first) {
+            (second, first)
+        } else {
+            (first, second)
+        };
+        a.iter()
+            .chain(b.iter())
+            .any(|w| w.compare_exchange(0, word, SeqCst, SeqCst).is_ok())
+    }
+
+    /// Remove a word which was inserted with the given hash. Returns false if it wasn't there.
+    pub fn remove(&self, hash: u64, word: u64) -> bool {
+        self.candidates(hash)
+            .any(|w| w.compare_exchange(word, 0, SeqCst, SeqCst).is_ok())
+    }
+
+    /// All the positions where a word inserted with the given hash could be.
+    pub fn candidates(&self, hash: u64) -> impl Iterator<Item = &AtomicU64> {
+        let [first, second] = self.buckets(hash);
+        let second = if std::ptr::eq(first, second) { &[][..] } else { second };
+        first.iter().chain(second.iter())
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_remove() {
+        let t = Table::new(4);
+        assert!(t.insert(1, 10));
+        assert!(t.insert(1, 11));
+        assert!(t.candidates(1).any(|w| w.load(SeqCst) == 10));
+        assert!(t.remove(1, 10));
+        assert!(!t.remove(1, 10));
+        assert!(t.candidates(1).any(|w| w.load(SeqCst) == 11));
+        assert!(!t.candidates(1).any(|w| w.load(SeqCst) == 10));
+    }
+
+    #[test]
+    fn full() {
+        // Every hash maps to the same single bucket.
+        let t = Table::new(1);
+        for i in 1 ..= BUCKET as u64 {
+            assert!(t.insert(0, i));
+        }
+        assert!(!t.insert(0, 100));
+        assert!(t.remove(0, 3));
+        assert!(t.insert(0, 100));
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+
+use crate::{BuildError, S3Fifo, S3FifoBuilder};
+
+// Keys are sampled by comparing their hash modulo this with a threshold.
+const MODULUS: u64 = 1 << 24;
+
+/// Estimates the miss ratio of S3-FIFO at many cache sizes, in a single pass over a trace.
+///
+/// This uses SHARDS-style spatial sampling: only keys whose hash falls under a threshold are
+/// simulated, each in a set of miniature caches scaled down by the same rate as the trace. At a
+/// rate of 0.01, a 100x smaller trace is run through caches 100x smaller than the sizes asked
+/// for, which estimates their miss ratios well as long as the miniature caches still hold a few
+/// hundred entries. A rate of 1 simulates everything exactly.
+///
+/// Sizes are in entries, or in whatever the weigher measures:
+///
+/// ```
+/// use s3fifo::{MissRatioCurve, S3Fifo};
+///
+/// let sizes = MissRatioCurve::log_spaced_sizes(1 << 20, 1 << 30, 11);
+/// let mut mrc = MissRatioCurve::with_builder(sizes, 0.01, |capacity| {
+///     S3Fifo::builder().capacity(capacity).weigher(|_: &u64, size: &u32| *size as usize)
+/// })
+/// .unwrap();
+/// for (key, size) in [("a", 4096), ("b", 100), ("a", 4096)] {
+///     mrc.access(key, size);
+/// }
+/// let mut csv = vec![];
+/// mrc.write_csv(&mut csv).unwrap();
+/// ```
+pub struct MissRatioCurve {
+    sample_rate: f64,
+    threshold: u64,
+    sims: Vec<Sim>,
+}
+
+struct Sim {
+    size: usize,
+    cache: S3Fifo<u64, u32>,
+    requests: u64,
+    misses: u64,
+    bytes: u64,
+    missed_bytes: u64,
+}
+
+/// The estimated miss ratios of one cache size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct MrcPoint {
+    /// The full size of the cache, before scaling down.
+    pub size: usize,
+    /// Gets which were sampled and simulated at this size.
+    pub requests: u64,
+    /// Fraction of gets which missed.
+    pub miss_ratio: f64,
+    /// Fraction of the bytes requested by gets which missed.
+    pub byte_miss_ratio: f64,
+}
+
+impl MissRatioCurve {
+    /// Estimate the miss ratios at each of `sizes` of caches with the default settings, sampling
+    /// `sample_rate` of the keys.
+    ///
+    /// # Panics
+    ///
+    /// If `sample_rate` isn't in (0, 1].
+    pub fn new(sizes: impl IntoIterator<Item = usize>, sample_rate: f64) -> Self {
+        Self::with_builder(sizes, sample_rate, |capacity| S3Fifo::builder().capacity(capacity))
+            .expect("default settings are valid")
+    }
+
+    /// Like [`new`](Self::new), but with caches configured by `builder`, which is given the
+    /// scaled down capacity of each.
+    pub fn with_builder(
+        sizes: impl IntoIterator<Item = usize>,
+        sample_rate: f64,
+        mut builder: impl FnMut(usize) -> S3FifoBuilder<u64, u32>,
+    ) -> Result<Self, BuildError> {
+        assert!(
+            sample_rate > 0.0 && sample_rate <= 1.0,
+            "sample rate {sample_rate} is not in (0, 1]"
+        );
+        let threshold = ((MODULUS as f64 * sample_rate).round() as u64).max(1);
+        let sample_rate = threshold as f64 / MODULUS as f64;
+        let sims = sizes
+            .into_iter()
+            .map(|size| {
+                let scaled = (size as f64 * sample_rate).round() as usize;
+                Ok(Sim {
+                    size,
+                    cache: builder(scaled).build()?,
+                    requests: 0,
+                    misses: 0,
+                    bytes: 0,
+                    missed_bytes: 0,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { sample_rate, threshold, sims })
+    }
+
+    /// `count` sizes from `min` to `max`, evenly spaced on a log scale, without duplicates.
+    pub fn log_spaced_sizes(min: usize, max: usize, count: usize) -> Vec<usize> {
+        let min = min.max(1);
+        let max = max.max(min);
+        let ratio = (max as f64 / min as f64).ln();
+        let mut sizes: Vec<usize> = (0 .. count)
+            .map(|i| {
+                let fraction = i as f64 / count.saturating_sub(1).max(1) as f64;
+                (min as f64 * (ratio * fraction).exp()).round() as usize
+            })
+            .collect();
+        sizes.dedup();
+        sizes
+    }
+
+    /// The fraction of keys actually sampled, which is the requested rate rounded to what the
+    /// sampling can do.
+    pub fn sample_rate(&self) -> f64 {
+        self.sample_rate
+    }
+
+    // The sampled key's hash, which stands for it in the miniature caches.
+    fn sample<Q: Hash + ?Sized>(&self, key: &Q) -> Option<u64> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        (hash % MODULUS < self.threshold).then_some(hash)
+    }
+
+    /// Get `key`, whose value is `size` bytes, inserting it on a miss.
+    pub fn access<Q: Hash + ?Sized>(&mut self, key: &Q, size: u32) {
+        let Some(hash) = self.sample(key) else {
+            return;
+        };
+        for sim in &mut self.sims {
+            sim.requests += 1;
+            sim.bytes += u64::from(size);
+            if sim.cache.read(&hash).is_none() {
+                sim.misses += 1;
+                sim.missed_bytes += u64::from(size);
+                sim.cache.insert(hash, size);
+            }
+        }
+    }
+
+    /// Write `key`, whose value is `size` bytes, without counting it as a request.
+    pub fn insert<Q: Hash + ?Sized>(&mut self, key: &Q, size: u32) {
+        if let Some(hash) = self.sample(key) {
+            for sim in &mut self.sims {
+                sim.cache.insert(hash, size);
+            }
+        }
+    }
+
+    /// Delete `key`.
+    pub fn remove<Q: Hash + ?Sized>(&mut self, key: &Q) {
+        if let Some(hash) = self.sample(key) {
+            for sim in &mut self.sims {
+                sim.cache.remove(&hash);
+            }
+        }
+    }
+
+    /// The estimates so far, in the order the sizes were given.
+    pub fn points(&self) -> Vec<MrcPoint> {
+        self.sims
+            .iter()
+            .map(|sim| MrcPoint {
+                size: sim.size,
+                requests: sim.requests,
+                miss_ratio: sim.misses as f64 / sim.requests.max(1) as f64,
+                byte_miss_ratio: sim.missed_bytes as f64 / sim.bytes.max(1) as f64,
+            })
+            .collect()
+    }
+
+    /// Write the curve as CSV, with a header line and one line per size.
+    pub fn write_csv(&self, mut out: impl Write) -> io::Result<()> {
+        writeln!(out, "size,miss_ratio,byte_miss_ratio,sampled_requests")?;
+        for point in self.points() {
+            writeln!(
+                out,
+                "{},{},{},{}",
+                point.size, point.miss_ratio, point.byte_miss_ratio, point.requests
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write the curve as a JSON object, with the sample rate and an array of points.
+    pub fn write_json(&self, mut out: impl Write) -> io::Result<()> {
+        write!(out, "{{\"sample_rate\":{},\"points\":[", self.sample_rate)?;
+        for (i, point) in self.points().iter().enumerate() {
+            write!(
+                out,
+                concat!(
+                    "{}{{\"size\":{},\"miss_ratio\":{},",
+                    "\"byte_miss_ratio\":{},\"sampled_requests\":{}}}"
+                ),
+                if i == 0 { "" } else { "," },
+                point.size,
+                point.miss_ratio,
+                point.byte_miss_ratio,
+                point.requests
+            )?;
+        }
+        writeln!(out, "]}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    // Keys with a roughly Zipfian popularity.
+    fn trace(len: usize) -> Vec<u32> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        (0 .. len).map(|_| (1.0 / rng.gen::<f64>().max(1e-9)) as u32).collect()
+    }
+
+    fn exact(trace: &[u32], size: usize) -> f64 {
+        let mut cache = S3Fifo::builder().capacity(size).stats(true).build().unwrap();
+        for key in trace {
+            if cache.read(key).is_none() {
+                cache.insert(*key, ());
+            }
+        }
+        let stats = cache.stats();
+        stats.misses as f64 / (stats.hits + stats.misses) as f64
+    }
+
+    #[test]
+    fn matches_simulation() {
+        let trace = trace(200_000);
+        let sizes = MissRatioCurve::log_spaced_sizes(100, 10_000, 5);
+        assert_eq!(sizes, [100, 316, 1000, 3162, 10_000]);
+
+        // Without sampling, the curve is exact.
+        let mut mrc = MissRatioCurve::new(sizes.iter().copied(), 1.0);
+        for key in &trace {
+            mrc.access(key, 1);
+        }
+        for (point, &size) in mrc.points().iter().zip(&sizes) {
+            assert_eq!(point.miss_ratio, exact(&trace, size));
+            assert_eq!(point.byte_miss_ratio, point.miss_ratio);
+            assert_eq!(point.requests, trace.len() as u64);
+        }
+
+        // Sampling a tenth of the keys is close.
+        let sizes = [1000, 3000, 10_000];
+        let mut mrc = MissRatioCurve::new(sizes, 0.1);
+        for key in &trace {
+            mrc.access(key, 1);
+        }
+        for (point, size) in mrc.points().iter().zip(sizes) {
+            let error = (point.miss_ratio - exact(&trace, size)).abs();
+            assert!(error < 0.02, "{size}: {error}");
+        }
+    }
+
+    #[test]
+    fn output() {
+        let mut mrc = MissRatioCurve::new([20, 40], 1.0);
+        mrc.access(&1, 10);
+        mrc.access(&2, 30);
+        mrc.access(&1, 10);
+        mrc.remove(&1);
+        mrc.access(&1, 10);
+        let mut csv = vec![];
+        mrc.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "size,miss_ratio,byte_miss_ratio,sampled_requests\n20,0.75,0.8333333333333334,4\n\
+             40,0.75,0.8333333333333334,4\n"
+        );
+        let mut json = vec![];
+        MissRatioCurve::new([5], 1.0).write_json(&mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\"sample_rate\":1,\"points\":[{\"size\":5,\"miss_ratio\":0,\"byte_miss_ratio\":0,\
+             \"sampled_requests\":0}]}\n"
+        );
+    }
+}
@@ -0,0 +1,105 @@
+//! Other eviction policies to compare S3-FIFO with, and a trait over all of them.
+//!
+//! The baselines are simple, single-threaded implementations meant for simulations and
+//! benchmarks, and their capacities are numbers of entries.
+
+use std::hash::Hash;
+
+use crate::S3Fifo;
+
+mod arc;
+mod clock;
+mod fifo;
+mod list;
+mod lru;
+mod opt;
+mod sieve;
+
+pub use arc::ArcCache;
+pub use clock::ClockCache;
+pub use fifo::FifoCache;
+pub use lru::LruCache;
+pub use opt::OptCache;
+pub use sieve::SieveCache;
+
+/// What a simulator or benchmark needs from a cache, so it can be generic over the policy.
+pub trait CachePolicy<K, V> {
+    /// Look up a key, counting it as an access.
+    fn get(&mut self, key: &K) -> Option<&V>;
+
+    /// Insert a value, evicting whatever the policy chooses to make room, or replace the value
+    /// of a cached key, returning the previous one. A policy may decline to insert at all.
+    fn insert(&mut self, key: K, value: V) -> Option<V>;
+
+    fn remove(&mut self, key: &K) -> Option<V>;
+
+    /// Number of cached entries.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How much the cache can hold, in the units `len` counts, or the weigher's for `S3Fifo`.
+    fn capacity(&self) -> usize;
+}
+
+impl<K: Hash + Eq + Clone, V> CachePolicy<K, V> for S3Fifo<K, V> {
+    fn get(&mut self, key: &K) -> Option<&V> {
+        self.read(key)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        S3Fifo::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        S3Fifo::remove(self, key)
+    }
+
+    fn len(&self) -> usize {
+        S3Fifo::len(self)
+    }
+
+    fn capacity(&self) -> usize {
+        self.small_size + self.main_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workload::Workload;
+
+    fn misses(cache: &mut dyn CachePolicy<u64, u64>, trace: &[u64]) -> usize {
+        let mut misses = 0;
+        for &key in trace {
+            if cache.get(&key).is_none() {
+                misses += 1;
+                cache.insert(key, key);
+            }
+            assert!(cache.len() <= cache.capacity());
+        }
+        misses
+    }
+
+    #[test]
+    fn opt_is_best() {
+        let zipf = Workload::Zipf { keys: 10_000, skew: 1.0 };
+        let trace: Vec<u64> = zipf.generate(0).take(50_000).collect();
+        let capacity = 100;
+        let opt = misses(&mut OptCache::new(capacity, trace.iter().copied()), &trace);
+        let others: [Box<dyn CachePolicy<u64, u64>>; 6] = [
+            Box::new(S3Fifo::new(capacity / 10, capacity - capacity / 10)),
+            Box::new(LruCache::new(capacity)),
+            Box::new(FifoCache::new(capacity)),
+            Box::new(ClockCache::new(capacity)),
+            Box::new(SieveCache::new(capacity)),
+            Box::new(ArcCache::new(capacity)),
+        ];
+        for mut cache in others {
+            let n = misses(&mut *cache, &trace);
+            assert!(opt < n, "{opt} >= {n}");
+        }
+    }
+}
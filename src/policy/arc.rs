@@ -0,0 +1,144 @@
+use std::hash::Hash;
+
+use super::list::KeyedList;
+use super::CachePolicy;
+
+/// Adaptive Replacement Cache, from "ARC: A Self-Tuning, Low Overhead Replacement Cache" by
+/// Nimrod Megiddo and Dharmendra S. Modha.
+///
+/// Entries seen once live in an LRU list `t1`, and entries seen again in another, `t2`. Keys
+/// evicted from each are remembered in ghost lists `b1` and `b2`, and a hit in either moves the
+/// target size of `t1` towards the list that would have kept it.
+pub struct ArcCache<K, V> {
+    t1: KeyedList<K, V>,
+    t2: KeyedList<K, V>,
+    b1: KeyedList<K, ()>,
+    b2: KeyedList<K, ()>,
+    // Target size of t1.
+    p: usize,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> ArcCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            t1: KeyedList::new(),
+            t2: KeyedList::new(),
+            b1: KeyedList::new(),
+            b2: KeyedList::new(),
+            p: 0,
+            capacity,
+        }
+    }
+
+    /// Evict from t1 or t2 into its ghost list, if the cache is full. The paper's REPLACE.
+    fn replace(&mut self, in_b2: bool) {
+        if self.t1.len() + self.t2.len() < self.capacity {
+            return;
+        }
+        let t1 = self.t1.len();
+        if t1 > 0 && (t1 > self.p || (in_b2 && t1 == self.p)) {
+            if let Some((key, _)) = self.t1.pop_back() {
+                self.b1.push_front(key, ());
+            }
+        } else if let Some((key, _)) = self.t2.pop_back() {
+            self.b2.push_front(key, ());
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> CachePolicy<K, V> for ArcCache<K, V> {
+    fn get(&mut self, key: &K) -> Option<&V> {
+        if let Some(idx) = self.t1.find(key) {
+            let (key, value) = self.t1.remove(idx);
+            let idx = self.t2.push_front(key, value);
+            return Some(&self.t2.node(idx).value);
+        }
+        let idx = self.t2.find(key)?;
+        self.t2.move_to_front(idx);
+        Some(&self.t2.node(idx).value)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(idx) = self.t1.find(&key) {
+            return Some(std::mem::replace(&mut self.t1.node_mut(idx).value, value));
+        }
+        if let Some(idx) = self.t2.find(&key) {
+            return Some(std::mem::replace(&mut self.t2.node_mut(idx).value, value));
+        }
+        let c = self.capacity;
+        if c == 0 {
+            return None;
+        }
+        if let Some(idx) = self.b1.find(&key) {
+            let delta = (self.b2.len() / self.b1.len()).max(1);
+            self.p = (self.p + delta).min(c);
+            self.b1.remove(idx);
+            self.replace(false);
+            self.t2.push_front(key, value);
+        } else if let Some(idx) = self.b2.find(&key) {
+            let delta = (self.b1.len() / self.b2.len()).max(1);
+            self.p = self.p.saturating_sub(delta);
+            self.b2.remove(idx);
+            self.replace(true);
+            self.t2.push_front(key, value);
+        } else {
+            let l1 = self.t1.len() + self.b1.len();
+            let total = l1 + self.t2.len() + self.b2.len();
+            if l1 >= c {
+                if self.t1.len() < c {
+                    self.b1.pop_back();
+                    self.replace(false);
+                } else {
+                    self.t1.pop_back();
+                }
+            } else if total >= c {
+                if total >= 2 * c {
+                    self.b2.pop_back();
+                }
+                self.replace(false);
+            }
+            self.t1.push_front(key, value);
+        }
+        None
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        if let Some(idx) = self.t1.find(key) {
+            return Some(self.t1.remove(idx).1);
+        }
+        let idx = self.t2.find(key)?;
+        Some(self.t2.remove(idx).1)
+    }
+
+    fn len(&self) -> usize {
+        self.t1.len() + self.t2.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adapts() {
+        let mut cache = ArcCache::new(2);
+        cache.insert(1, 1);
+        cache.get(&1);
+        cache.insert(2, 2);
+        cache.insert(3, 3);
+        // 2 was only seen once, so it went to b1, and 1 is still in t2.
+        let cached: Vec<_> = [1, 2].iter().map(|key| cache.get(key).copied()).collect();
+        assert_eq!(cached, [Some(1), None]);
+        assert_eq!((cache.t1.len(), cache.b1.len()), (1, 1));
+        // Coming back from b1 grows t1's target.
+        cache.insert(2, 2);
+        assert_eq!(cache.p, 1);
+        assert!(cache.t2.find(&2).is_some());
+        assert!(cache.len() <= 2);
+    }
+}
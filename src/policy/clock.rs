@@ -0,0 +1,84 @@
+use std::hash::Hash;
+
+use super::list::KeyedList;
+use super::CachePolicy;
+
+/// CLOCK, or FIFO with reinsertion: hits set a visited bit, and an entry found visited at the
+/// tail has its bit cleared and goes back to the head instead of being evicted.
+pub struct ClockCache<K, V> {
+    list: KeyedList<K, V>,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> ClockCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self { list: KeyedList::new(), capacity }
+    }
+
+    fn evict(&mut self) {
+        while let Some(idx) = self.list.tail() {
+            let node = self.list.node_mut(idx);
+            if !node.visited {
+                self.list.remove(idx);
+                return;
+            }
+            node.visited = false;
+            self.list.move_to_front(idx);
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> CachePolicy<K, V> for ClockCache<K, V> {
+    fn get(&mut self, key: &K) -> Option<&V> {
+        let idx = self.list.find(key)?;
+        let node = self.list.node_mut(idx);
+        node.visited = true;
+        Some(&node.value)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(idx) = self.list.find(&key) {
+            return Some(std::mem::replace(&mut self.list.node_mut(idx).value, value));
+        }
+        if self.capacity == 0 {
+            return None;
+        }
+        if self.list.len() == self.capacity {
+            self.evict();
+        }
+        self.list.push_front(key, value);
+        None
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let idx = self.list.find(key)?;
+        Some(self.list.remove(idx).1)
+    }
+
+    fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_chance() {
+        let mut cache = ClockCache::new(3);
+        for key in 1 ..= 3 {
+            cache.insert(key, key);
+        }
+        cache.get(&1);
+        // 1 goes round again, so 2 is evicted, and then 3.
+        cache.insert(4, 4);
+        cache.insert(5, 5);
+        let cached: Vec<_> = [1, 2, 3].iter().map(|key| cache.get(key).copied()).collect();
+        assert_eq!(cached, [Some(1), None, None]);
+    }
+}
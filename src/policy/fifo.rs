@@ -0,0 +1,68 @@
+use std::hash::Hash;
+
+use super::list::KeyedList;
+use super::CachePolicy;
+
+/// First in, first out: entries are evicted in the order they were inserted, however often they
+/// are read.
+pub struct FifoCache<K, V> {
+    list: KeyedList<K, V>,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> FifoCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self { list: KeyedList::new(), capacity }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> CachePolicy<K, V> for FifoCache<K, V> {
+    fn get(&mut self, key: &K) -> Option<&V> {
+        let idx = self.list.find(key)?;
+        Some(&self.list.node(idx).value)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(idx) = self.list.find(&key) {
+            return Some(std::mem::replace(&mut self.list.node_mut(idx).value, value));
+        }
+        if self.capacity == 0 {
+            return None;
+        }
+        if self.list.len() == self.capacity {
+            self.list.pop_back();
+        }
+        self.list.push_front(key, value);
+        None
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let idx = self.list.find(key)?;
+        Some(self.list.remove(idx).1)
+    }
+
+    fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_oldest() {
+        let mut cache = FifoCache::new(2);
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.get(&1);
+        assert_eq!(cache.insert(2, 20), Some(2));
+        cache.insert(3, 3);
+        let cached: Vec<_> = [1, 2, 3].iter().map(|key| cache.get(key).copied()).collect();
+        assert_eq!(cached, [None, Some(20), Some(3)]);
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+const NIL: usize = usize::MAX;
+
+pub(crate) struct Node<K, V> {
+    pub key: K,
+    pub value: V,
+    // Whether the entry was accessed, for the policies which keep track.
+    pub visited: bool,
+    prev: usize,
+    next: usize,
+}
+
+/// A linked list of entries in a slab, with an index to find them by key, shared by the
+/// baseline policies. The head is the newest end.
+pub(crate) struct KeyedList<K, V> {
+    slots: Vec<Option<Node<K, V>>>,
+    free: Vec<usize>,
+    index: HashMap<K, usize>,
+    head: usize,
+    tail: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> KeyedList<K, V> {
+    pub fn new() -> Self {
+        Self {
+            slots: vec![],
+            free: vec![],
+            index: HashMap::new(),
+            head: NIL,
+            tail: NIL,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn find(&self, key: &K) -> Option<usize> {
+        self.index.get(key).copied()
+    }
+
+    pub fn node(&self, idx: usize) -> &Node<K, V> {
+        self.slots[idx].as_ref().expect("linked slot is occupied")
+    }
+
+    pub fn node_mut(&mut self, idx: usize) -> &mut Node<K, V> {
+        self.slots[idx].as_mut().expect("linked slot is occupied")
+    }
+
+    pub fn tail(&self) -> Option<usize> {
+        (self.tail != NIL).then_some(self.tail)
+    }
+
+    /// The entry after `idx` towards the head.
+    pub fn prev(&self, idx: usize) -> Option<usize> {
+        let prev = self.node(idx).prev;
+        (prev != NIL).then_some(prev)
+    }
+
+    pub fn push_front(&mut self, key: K, value: V) -> usize {
+        let node = Node {
+            key: key.clone(),
+            value,
+            visited: false,
+            prev: NIL,
+            next: NIL,
+        };
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.slots[idx] = Some(node);
+                idx
+            }
+            None => {
+                self.slots.push(Some(node));
+                self.slots.len() - 1
+            }
+        };
+        self.index.insert(key, idx);
+        self.link_front(idx);
+        idx
+    }
+
+    pub fn move_to_front(&mut self, idx: usize) {
+        self.unlink(idx);
+        self.link_front(idx);
+    }
+
+    pub fn remove(&mut self, idx: usize) -> (K, V) {
+        self.unlink(idx);
+        let node = self.slots[idx].take().expect("linked slot is occupied");
+        self.free.push(idx);
+        self.index.remove(&node.key);
+        (node.key, node.value)
+    }
+
+    pub fn pop_back(&mut self) -> Option<(K, V)> {
+        self.tail().map(|idx| self.remove(idx))
+    }
+
+    fn link_front(&mut self, idx: usize) {
+        let head = self.head;
+        let node = self.node_mut(idx);
+        node.prev = NIL;
+        node.next = head;
+        if head == NIL {
+            self.tail = idx;
+        } else {
+            self.node_mut(head).prev = idx;
+        }
+        self.head = idx;
+    }
+
+    fn unlink(&mut self, idx: usize) {
+        let (prev, next) = (self.node(idx).prev, self.node(idx).next);
+        if prev == NIL {
+            self.head = next;
+        } else {
+            self.node_mut(prev).next = next;
+        }
+        if next == NIL {
+            self.tail = prev;
+        } else {
+            self.node_mut(next).prev = prev;
+        }
+    }
+}
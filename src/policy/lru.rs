@@ -0,0 +1,68 @@
+use std::hash::Hash;
+
+use super::list::KeyedList;
+use super::CachePolicy;
+
+/// Least recently used: every hit moves the entry to the head, and the tail is evicted.
+pub struct LruCache<K, V> {
+    list: KeyedList<K, V>,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self { list: KeyedList::new(), capacity }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> CachePolicy<K, V> for LruCache<K, V> {
+    fn get(&mut self, key: &K) -> Option<&V> {
+        let idx = self.list.find(key)?;
+        self.list.move_to_front(idx);
+        Some(&self.list.node(idx).value)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(idx) = self.list.find(&key) {
+            self.list.move_to_front(idx);
+            return Some(std::mem::replace(&mut self.list.node_mut(idx).value, value));
+        }
+        if self.capacity == 0 {
+            return None;
+        }
+        if self.list.len() == self.capacity {
+            self.list.pop_back();
+        }
+        self.list.push_front(key, value);
+        None
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let idx = self.list.find(key)?;
+        Some(self.list.remove(idx).1)
+    }
+
+    fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recent() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        cache.get(&1);
+        cache.insert(3, 3);
+        let cached: Vec<_> = [1, 2, 3].iter().map(|key| cache.get(key).copied()).collect();
+        assert_eq!(cached, [Some(1), None, Some(3)]);
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+use super::CachePolicy;
+
+/// Belady's optimal policy, which knows the future: it evicts whichever entry is needed again
+/// furthest ahead, and doesn't insert a key at all if that key is the one needed furthest ahead.
+/// Its miss ratio is a lower bound for any policy on the same trace.
+///
+/// The trace has to be given up front, as the key of every get in order, and then replayed
+/// through the cache exactly: every get, in the same order, each followed by an insert if it
+/// missed. Inserts of keys which aren't being got don't count as requests, and remove does
+/// nothing to the trace either.
+pub struct OptCache<K, V> {
+    // For each request in the trace, the position of the next request for the same key.
+    next_use: Vec<usize>,
+    position: usize,
+    // When each key is next requested, as of the current position.
+    upcoming: HashMap<K, usize>,
+    entries: HashMap<K, (V, usize)>,
+    by_next_use: BTreeMap<usize, K>,
+    // Keys which are never requested again get distinct positions past the end of the trace.
+    never: usize,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> OptCache<K, V> {
+    pub fn new(capacity: usize, trace: impl IntoIterator<Item = K>) -> Self {
+        let trace: Vec<K> = trace.into_iter().collect();
+        let mut next_use = vec![usize::MAX; trace.len()];
+        let mut upcoming = HashMap::new();
+        for (i, key) in trace.into_iter().enumerate().rev() {
+            if let Some(next) = upcoming.insert(key, i) {
+                next_use[i] = next;
+            }
+        }
+        Self {
+            never: next_use.len(),
+            next_use,
+            position: 0,
+            upcoming,
+            entries: HashMap::new(),
+            by_next_use: BTreeMap::new(),
+            capacity,
+        }
+    }
+
+    fn next_use(&mut self, key: &K) -> usize {
+        match self.upcoming.get(key) {
+            Some(&next) if next != usize::MAX => next,
+            _ => {
+                self.never += 1;
+                self.never
+            }
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> CachePolicy<K, V> for OptCache<K, V> {
+    fn get(&mut self, key: &K) -> Option<&V> {
+        if let Some(next) = self.upcoming.get_mut(key) {
+            debug_assert_eq!(*next, self.position, "requests don't follow the trace");
+            *next = self.next_use.get(self.position).copied().unwrap_or(usize::MAX);
+        }
+        self.position += 1;
+        let next = self.next_use(key);
+        let (value, old) = self.entries.get_mut(key)?;
+        self.by_next_use.remove(old);
+        *old = next;
+        self.by_next_use.insert(next, key.clone());
+        Some(value)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let next = self.next_use(&key);
+        if let Some((old_value, old)) = self.entries.get_mut(&key) {
+            self.by_next_use.remove(old);
+            *old = next;
+            self.by_next_use.insert(next, key);
+            return Some(std::mem::replace(old_value, value));
+        }
+        if self.entries.len() >= self.capacity {
+            match self.by_next_use.last_key_value() {
+                Some((&furthest, _)) if furthest > next => {
+                    let (_, evicted) = self.by_next_use.pop_last().unwrap();
+                    self.entries.remove(&evicted);
+                }
+                _ => return None,
+            }
+        }
+        self.by_next_use.insert(next, key.clone());
+        self.entries.insert(key, (value, next));
+        None
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, next) = self.entries.remove(key)?;
+        self.by_next_use.remove(&next);
+        Some(value)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_furthest() {
+        let trace = [1, 2, 3, 1, 2, 1, 3];
+        let mut cache = OptCache::new(2, trace);
+        let mut misses = vec![];
+        for key in trace {
+            if cache.get(&key).is_none() {
+                misses.push(key);
+                cache.insert(key, ());
+            }
+        }
+        // 3 is never worth keeping: the first time, 1 and 2 are needed sooner, and the second
+        // time, it's the last request.
+        assert_eq!(misses, [1, 2, 3, 3]);
+    }
+}
@@ -0,0 +1,94 @@
+use std::hash::Hash;
+
+use super::list::KeyedList;
+use super::CachePolicy;
+
+/// SIEVE: like CLOCK, hits set a visited bit, but visited entries stay where they are. A hand
+/// sweeps from the tail towards the head, clearing bits, and evicts the first entry it finds
+/// unvisited, then carries on from there next time.
+pub struct SieveCache<K, V> {
+    list: KeyedList<K, V>,
+    capacity: usize,
+    hand: Option<usize>,
+}
+
+impl<K: Hash + Eq + Clone, V> SieveCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self { list: KeyedList::new(), capacity, hand: None }
+    }
+
+    fn evict(&mut self) {
+        let mut idx = match self.hand.or(self.list.tail()) {
+            Some(idx) => idx,
+            None => return,
+        };
+        while self.list.node(idx).visited {
+            self.list.node_mut(idx).visited = false;
+            idx = self.list.prev(idx).or(self.list.tail()).expect("list isn't empty");
+        }
+        self.hand = self.list.prev(idx);
+        self.list.remove(idx);
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> CachePolicy<K, V> for SieveCache<K, V> {
+    fn get(&mut self, key: &K) -> Option<&V> {
+        let idx = self.list.find(key)?;
+        let node = self.list.node_mut(idx);
+        node.visited = true;
+        Some(&node.value)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(idx) = self.list.find(&key) {
+            return Some(std::mem::replace(&mut self.list.node_mut(idx).value, value));
+        }
+        if self.capacity == 0 {
+            return None;
+        }
+        if self.list.len() == self.capacity {
+            self.evict();
+        }
+        self.list.push_front(key, value);
+        None
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let idx = self.list.find(key)?;
+        if self.hand == Some(idx) {
+            self.hand = self.list.prev(idx);
+        }
+        Some(self.list.remove(idx).1)
+    }
+
+    fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hand_stays() {
+        let mut cache = SieveCache::new(3);
+        for key in 1 ..= 3 {
+            cache.insert(key, key);
+        }
+        cache.get(&1);
+        cache.get(&3);
+        // The hand passes 1, evicts 2, and stops at 3.
+        cache.insert(4, 4);
+        assert_eq!(cache.get(&2), None);
+        // The hand carries on from 3, clearing its bit, and evicts 4, even though 1 is older and
+        // its bit was cleared already.
+        cache.insert(5, 5);
+        let cached: Vec<_> = [1, 3, 4].iter().map(|key| cache.get(key).copied()).collect();
+        assert_eq!(cached, [Some(1), Some(3), None]);
+    }
+}
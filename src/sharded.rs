@@ -0,0 +1,658 @@
+//! A thread-safe cache made of several independently locked `S3Fifo` shards.
+
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::convert::Infallible;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+#[cfg(feature = "async")]
+use crate::flight::Landing;
+use crate::flight::{Flights, Leader, Outcome, Role, SharedError};
+use crate::{Clock, EvictionListener, Rejected, S3Fifo, Stats, Weigher};
+
+/// A concurrent S3-FIFO cache, which can be shared between threads and used through `&self`.
+///
+/// Keys are spread over a number of shards by their hash, and each shard is a complete `S3Fifo`
+/// behind its own lock, holding an equal part of the overall capacity. Reads only take a shared
+/// lock, since `S3Fifo` counts accesses with atomics, so they only wait on writes to the same
+/// shard.
+///
+/// Because eviction decisions are made per shard, this is an approximation of a single S3-FIFO
+/// cache of the total size, which gets better the more entries each shard holds.
+///
+/// Misses loaded through `get_or_insert_with` or `try_get_or_insert_with` are coalesced: while
+/// one thread loads a key, other threads asking for the same key wait for its value rather than
+/// loading it too.
+pub struct ShardedS3Fifo<K, V> {
+    shards: Box<[RwLock<S3Fifo<K, V>>]>,
+    // Loads in progress, for the keys of the shard at the same index.
+    flights: Box<[Flights<K, V>]>,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq + Clone, V> ShardedS3Fifo<K, V> {
+    /// Create a cache holding up to `small` entries in the small queues and `main` entries in the
+    /// main queues overall, with a number of shards picked based on the available parallelism.
+    pub fn new(small: usize, main: usize) -> Self {
+        Self::with_shards(small, main, default_shards(small, main))
+    }
+
+    /// Like `new`, but with a given number of shards.
+    pub fn with_shards(small: usize, main: usize, shards: usize) -> Self {
+        Self::build(small, main, shards, |small, main| S3Fifo::new(small, main))
+    }
+
+    /// Create a cache where `small` and `main` are the total weight of the small and main queues
+    /// across all shards, as measured by the given weigher. See [`S3Fifo::with_weigher`].
+    ///
+    /// Each shard gets its share of the weight, so the heaviest entry the cache can hold weighs
+    /// `main / shards`, not `main`.
+    pub fn with_weigher(
+        small: usize,
+        main: usize,
+        shards: usize,
+        weigher: impl Weigher<K, V> + Clone + Send + Sync + 'static,
+    ) -> Self {
+        Self::build(small, main, shards, |small, main| {
+            S3Fifo::with_weigher(small, main, weigher.clone())
+        })
+    }
+
+    fn build(
+        small: usize,
+        main: usize,
+        shards: usize,
+        mut new: impl FnMut(usize, usize) -> S3Fifo<K, V>,
+    ) -> Self {
+        assert!(shards > 0, "a cache needs at least one shard");
+        let flights = (0 .. shards).map(|_| Flights::new()).collect();
+        let shards = (0 .. shards)
+            .map(|i| RwLock::new(new(split(small, shards, i), split(main, shards, i))))
+            .collect();
+        Self {
+            shards,
+            flights,
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Set a listener to be told about every entry leaving the cache, from any shard.
+    ///
+    /// The listener runs while the entry's shard is locked, so it must not use the cache.
+    pub fn set_eviction_listener(
+        &self,
+        listener: impl EvictionListener<K, V> + Send + Sync + 'static,
+    ) {
+        let listener = Arc::new(listener);
+        for shard in self.shards.iter() {
+            lock_write(shard).set_eviction_listener(Arc::clone(&listener));
+        }
+    }
+
+    /// See [`S3Fifo::set_time_to_live`].
+    pub fn set_time_to_live(&self, ttl: Option<Duration>) {
+        for shard in self.shards.iter() {
+            lock_write(shard).set_time_to_live(ttl);
+        }
+    }
+
+    /// See [`S3Fifo::set_time_to_idle`].
+    pub fn set_time_to_idle(&self, tti: Option<Duration>) {
+        for shard in self.shards.iter() {
+            lock_write(shard).set_time_to_idle(tti);
+        }
+    }
+
+    /// See [`S3Fifo::set_clock`].
+    pub fn set_clock(&self, clock: impl Clock + Send + Sync + 'static) {
+        let clock = Arc::new(clock);
+        for shard in self.shards.iter() {
+            lock_write(shard).set_clock(Arc::clone(&clock));
+        }
+    }
+
+    /// Start counting hits, misses and queue movements in every shard. See [`S3Fifo::stats`].
+    pub fn enable_stats(&self) {
+        for shard in self.shards.iter() {
+            lock_write(shard).enable_stats();
+        }
+    }
+
+    /// The statistics of all shards added up. Shards are read one at a time, so this is not an
+    /// atomic snapshot when other threads are using the cache.
+    pub fn stats(&self) -> Stats {
+        self.shards
+            .iter()
+            .map(|shard| lock_read(shard).stats())
+            .fold(Stats::default(), |a, b| a + b)
+    }
+
+    pub fn reset_stats(&self) {
+        for shard in self.shards.iter() {
+            lock_read(shard).reset_stats();
+        }
+    }
+
+    /// Change the total capacities of the small, main and ghost queues, splitting them between
+    /// the shards as on creation. See [`S3Fifo::set_capacities`].
+    pub fn set_capacities(&self, small: usize, main: usize, ghost: usize) -> Vec<(K, V)> {
+        let n = self.shards.len();
+        let mut evicted = Vec::new();
+        for (i, shard) in self.shards.iter().enumerate() {
+            let mut shard = lock_write(shard);
+            evicted.extend(shard.set_capacities(
+                split(small, n, i),
+                split(main, n, i),
+                split(ghost, n, i),
+            ));
+        }
+        evicted
+    }
+
+    /// Change the total capacity, keeping the proportions of each shard's queues. See
+    /// [`S3Fifo::resize`].
+    pub fn resize(&self, total: usize) -> Vec<(K, V)> {
+        let n = self.shards.len();
+        let mut evicted = Vec::new();
+        for (i, shard) in self.shards.iter().enumerate() {
+            evicted.extend(lock_write(shard).resize(split(total, n, i)));
+        }
+        evicted
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.write(&key).insert(key, value)
+    }
+
+    pub fn insert_reset(&self, key: K, value: V) -> Option<V> {
+        self.write(&key).insert_reset(key, value)
+    }
+
+    pub fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) -> Option<V> {
+        self.write(&key).insert_with_ttl(key, value, ttl)
+    }
+
+    pub fn try_insert(&self, key: K, value: V) -> Result<Option<V>, Rejected<K, V>> {
+        self.write(&key).try_insert(key, value)
+    }
+
+    /// Look up a key and return a clone of its value.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.read(key, V::clone)
+    }
+
+    /// Look up a key and call a function with a reference to its value, returning the result.
+    ///
+    /// The shard stays locked for reading while the function runs.
+    pub fn read<Q, R>(&self, key: &Q, f: impl FnOnce(&V) -> R) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.read_shard(key).read(key).map(f)
+    }
+
+    /// Look up a key without counting it as an access, and return a clone of its value. See
+    /// [`S3Fifo::peek`].
+    pub fn peek<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.read_shard(key).peek(key).cloned()
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.read_shard(key).contains_key(key)
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.write(key).remove(key)
+    }
+
+    pub fn purge<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.write(key).purge(key)
+    }
+
+    /// Keep only the entries for which the predicate returns true.
+    ///
+    /// Shards are visited one at a time, so this is not atomic with respect to other threads.
+    pub fn retain(&self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        for shard in self.shards.iter() {
+            lock_write(shard).retain(&mut f);
+        }
+    }
+
+    pub fn remove_expired(&self) {
+        for shard in self.shards.iter() {
+            lock_write(shard).remove_expired();
+        }
+    }
+
+    pub fn invalidate_all(&self) {
+        for shard in self.shards.iter() {
+            lock_write(shard).invalidate_all();
+        }
+    }
+
+    pub fn clear(&self) {
+        for shard in self.shards.iter() {
+            lock_write(shard).clear();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| lock_read(shard).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| lock_read(shard).is_empty())
+    }
+
+    pub fn weight(&self) -> usize {
+        self.shards.iter().map(|shard| lock_read(shard).weight()).sum()
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard<Q>(&self, key: &Q) -> &RwLock<S3Fifo<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        &self.shards[self.index(key)]
+    }
+
+    fn index<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key);
+        (hash % self.shards.len() as u64) as usize
+    }
+
+    fn read_shard<Q>(&self, key: &Q) -> RwLockReadGuard<'_, S3Fifo<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        lock_read(self.shard(key))
+    }
+
+    fn write<Q>(&self, key: &Q) -> RwLockWriteGuard<'_, S3Fifo<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        lock_write(self.shard(key))
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> ShardedS3Fifo<K, V> {
+    /// Get the value of a key, or compute and insert it if it isn't cached.
+    ///
+    /// If other threads ask for the same key while it's being computed, they wait for the value
+    /// instead of computing it again. If `f` panics, the panic carries on in this thread, and one
+    /// of the waiting threads computes the value instead, with its own function.
+    ///
+    /// The value is returned even if it's too heavy to be cached.
+    pub fn get_or_insert_with(&self, key: K, f: impl FnOnce() -> V) -> V {
+        match self.try_get_or_insert_with(key, || Ok::<_, Infallible>(f())) {
+            Ok(value) => value,
+            Err(error) => match *error {},
+        }
+    }
+
+    /// Like `get_or_insert_with`, but the value is computed by a function which can fail. An
+    /// error is returned to this thread and to every thread which was waiting for the same load,
+    /// and nothing is inserted, so the next call tries again.
+    ///
+    /// Errors are shared through an `Arc`. A waiting thread whose function has a different error
+    /// type than the one which failed computes the value itself instead.
+    pub fn try_get_or_insert_with<E: Send + Sync + 'static>(
+        &self,
+        key: K,
+        f: impl FnOnce() -> Result<V, E>,
+    ) -> Result<V, Arc<E>> {
+        loop {
+            match self.join(&key) {
+                Role::Cached(value) => return Ok(value),
+                Role::Leader(leader) => return self.land(leader, key, f()),
+                Role::Follower(flight) => {
+                    if let Some(result) = landed(flight.wait()) {
+                        return result;
+                    }
+                }
+            }
+        }
+    }
+
+    /// The value of a key if it's cached, or else the load of it to join or lead.
+    fn join(&self, key: &K) -> Role<'_, K, V> {
+        if let Some(value) = self.get(key) {
+            return Role::Cached(value);
+        }
+        self.flights[self.index(key)].join(key, || self.peek(key))
+    }
+
+    /// Insert what a leader loaded, and hand it, or its error, to the waiters.
+    fn land<E: Send + Sync + 'static>(
+        &self,
+        leader: Leader<'_, K, V>,
+        key: K,
+        loaded: Result<V, E>,
+    ) -> Result<V, Arc<E>> {
+        match loaded {
+            Ok(value) => {
+                self.insert(key, value.clone());
+                leader.land(Outcome::Loaded(value.clone()));
+                Ok(value)
+            }
+            Err(error) => {
+                let error = Arc::new(error);
+                leader.land(Outcome::Failed(Arc::clone(&error) as SharedError));
+                Err(error)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<K: Hash + Eq + Clone, V: Clone> ShardedS3Fifo<K, V> {
+    /// Get the value of a key, or await `init` and insert its output if the key isn't cached.
+    ///
+    /// As with [`get_or_insert_with`](Self::get_or_insert_with), concurrent calls for the same
+    /// key wait for the first one's value rather than awaiting their own `init`. If the future
+    /// doing the load is dropped before it finishes, or `init` panics, one of the waiting futures
+    /// takes over with its own `init`.
+    ///
+    /// This works with any executor, and no lock is held across an await.
+    ///
+    /// Needs the `async` feature.
+    pub async fn get_with(&self, key: K, init: impl Future<Output = V>) -> V {
+        match self.try_get_with(key, async { Ok::<_, Infallible>(init.await) }).await {
+            Ok(value) => value,
+            Err(error) => match *error {},
+        }
+    }
+
+    /// Like `get_with`, but with a future which can fail. The error is shared with every waiting
+    /// future as in [`try_get_or_insert_with`](Self::try_get_or_insert_with).
+    ///
+    /// Needs the `async` feature.
+    pub async fn try_get_with<E: Send + Sync + 'static>(
+        &self,
+        key: K,
+        init: impl Future<Output = Result<V, E>>,
+    ) -> Result<V, Arc<E>> {
+        loop {
+            match self.join(&key) {
+                Role::Cached(value) => return Ok(value),
+                Role::Leader(leader) => return self.land(leader, key, init.await),
+                Role::Follower(flight) => {
+                    if let Some(result) = landed(Landing(flight).await) {
+                        return result;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// What a waiter gets from a flight it joined, or `None` if it has to load the key itself: when
+/// the leader gave up, or failed with an error of another type than the waiter's.
+fn landed<V, E: Send + Sync + 'static>(outcome: Outcome<V>) -> Option<Result<V, Arc<E>>> {
+    match outcome {
+        Outcome::Loaded(value) => Some(Ok(value)),
+        Outcome::Failed(error) => error.downcast().ok().map(Err),
+        Outcome::Abandoned => None,
+    }
+}
+
+// S3Fifo only calls out to user code (the weigher, eviction listener, or a retain predicate) at
+// points where its queues are consistent, so a panic in another thread doesn't make a shard
+// unusable.
+fn lock_read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn lock_write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Size of the `i`th of `n` parts of `total`, such that the parts add up to `total`.
+fn split(total: usize, n: usize, i: usize) -> usize {
+    total / n + usize::from(i < total % n)
+}
+
+fn default_shards(small: usize, main: usize) -> usize {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    // Don't split either queue so finely that shards end up without one, since a shard without a
+    // main queue can't cache anything.
+    (threads * 4).next_power_of_two().min(small.min(main).max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+
+    #[test]
+    fn capacity_split() {
+        let cache = ShardedS3Fifo::<u32, u32>::with_shards(10, 101, 4);
+        let sizes: Vec<_> = cache
+            .shards
+            .iter()
+            .map(|s| {
+                let s = s.read().unwrap();
+                (s.small_size, s.main_size)
+            })
+            .collect();
+        assert_eq!(sizes, vec![(3, 26), (3, 25), (2, 25), (2, 25)]);
+        assert!(ShardedS3Fifo::<u32, u32>::new(2, 20).shard_count() <= 2);
+        // Every shard gets a main queue too, so that it can cache something.
+        assert_eq!(default_shards(100, 1), 1);
+        let cache = ShardedS3Fifo::<u32, u32>::new(100, 10);
+        assert!(cache.shard_count() <= 10);
+        for k in 0 .. 10 {
+            cache.insert(k, k);
+            assert_eq!(cache.get(&k), Some(k));
+        }
+
+        let cache = ShardedS3Fifo::<String, u32>::with_shards(4, 4, 2);
+        cache.insert("a".to_string(), 1);
+        assert_eq!((cache.get("a"), cache.peek("a")), (Some(1), Some(1)));
+        assert!(cache.contains_key("a"));
+        assert_eq!(cache.remove("a"), Some(1));
+    }
+
+    #[test]
+    fn threads() {
+        fn assert_sync<T: Send + Sync>(_: &T) {}
+
+        let cache = ShardedS3Fifo::<u32, u32>::with_shards(20, 200, 8);
+        assert_sync(&cache);
+        cache.enable_stats();
+        std::thread::scope(|s| {
+            for t in 0 .. 8 {
+                let cache = &cache;
+                s.spawn(move || {
+                    let mut rng = rand::rngs::StdRng::seed_from_u64(t);
+                    for _ in 0 .. 10_000 {
+                        let k = rng.gen_range(0..500);
+                        match rng.gen_range(0..10) {
+                            0 => {
+                                cache.remove(&k);
+                            }
+                            1 ..= 4 => {
+                                cache.insert(k, k * 2);
+                            }
+                            _ => {
+                                if let Some(v) = cache.get(&k) {
+                                    assert_eq!(v, k * 2);
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        assert!(cache.len() <= 220);
+        let stats = cache.stats();
+        assert!(stats.hits > 0 && stats.misses > 0);
+        assert!(stats.inserts >= cache.len() as u64);
+        cache.reset_stats();
+        assert_eq!(cache.stats().hits, 0);
+        assert_eq!((cache.stats().small_size, cache.stats().main_size), (20, 200));
+        for shard in cache.shards.iter() {
+            shard.read().unwrap().check_invariants();
+        }
+    }
+
+    #[test]
+    fn single_flight() {
+        // Every thread misses at once, and the first to load takes long enough for the others to
+        // join it.
+        fn race<R: Send>(f: impl Fn() -> R + Sync) -> Vec<R> {
+            let barrier = Barrier::new(8);
+            std::thread::scope(|s| {
+                let handles: Vec<_> = (0 .. 8)
+                    .map(|_| {
+                        s.spawn(|| {
+                            barrier.wait();
+                            f()
+                        })
+                    })
+                    .collect();
+                handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+            })
+        }
+        fn slow<T>(value: T) -> T {
+            std::thread::sleep(Duration::from_millis(100));
+            value
+        }
+
+        let cache = ShardedS3Fifo::with_shards(4, 4, 2);
+        let calls = AtomicUsize::new(0);
+        let values = race(|| {
+            cache.get_or_insert_with(1, || slow(calls.fetch_add(1, Ordering::SeqCst) as u32 + 10))
+        });
+        assert_eq!(values, [10; 8]);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.get_or_insert_with(1, || unreachable!()), 10);
+
+        // Errors go to every waiter, and aren't cached.
+        let calls = AtomicUsize::new(0);
+        let results = race(|| {
+            cache.try_get_or_insert_with(2, || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                slow(Err::<u32, _>("down"))
+            })
+        });
+        assert!(results.iter().all(|result| matches!(result, Err(error) if **error == "down")));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.try_get_or_insert_with(2, || Ok::<_, ()>(20)), Ok(20));
+
+        // When the loader panics, a waiter loads instead.
+        let calls = AtomicUsize::new(0);
+        let results = race(|| {
+            catch_unwind(AssertUnwindSafe(|| {
+                cache.get_or_insert_with(3, || {
+                    if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                        slow(());
+                        panic!("loader failed");
+                    }
+                    slow(30)
+                })
+            }))
+        });
+        assert_eq!(results.iter().filter(|result| result.is_err()).count(), 1);
+        assert!(results.iter().flatten().all(|&value| value == 30));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(cache.flights.iter().all(|flights| flights.len() == 0));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn single_flight_async() {
+        use std::future::{pending, poll_fn};
+        use std::pin::pin;
+        use std::sync::atomic::AtomicBool;
+        use std::task::{Context, Poll, Wake, Waker};
+
+        struct Flag(AtomicBool);
+
+        impl Wake for Flag {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        fn assert_send<T: Send>(_: &T) {}
+
+        let woken = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(Arc::clone(&woken));
+        let mut cx = Context::from_waker(&waker);
+        let cache = ShardedS3Fifo::<u32, u32>::with_shards(4, 4, 2);
+
+        // The second future waits for the first one's value.
+        let ready = AtomicBool::new(false);
+        let mut leader = pin!(cache.get_with(1, async {
+            poll_fn(|_| {
+                if ready.load(Ordering::SeqCst) { Poll::Ready(()) } else { Poll::Pending }
+            })
+            .await;
+            10
+        }));
+        let mut follower = pin!(cache.get_with(1, async { unreachable!() }));
+        assert_send(&follower);
+        assert!(leader.as_mut().poll(&mut cx).is_pending());
+        assert!(follower.as_mut().poll(&mut cx).is_pending());
+        ready.store(true, Ordering::SeqCst);
+        assert_eq!(leader.poll(&mut cx), Poll::Ready(10));
+        assert!(woken.0.swap(false, Ordering::SeqCst));
+        assert_eq!(follower.poll(&mut cx), Poll::Ready(10));
+
+        // Dropping the leading future hands the load to a waiting one.
+        let mut leader = Box::pin(cache.try_get_with(2, pending::<Result<u32, ()>>()));
+        let mut follower = pin!(cache.try_get_with(2, async { Err::<u32, _>("down") }));
+        assert!(leader.as_mut().poll(&mut cx).is_pending());
+        assert!(follower.as_mut().poll(&mut cx).is_pending());
+        drop(leader);
+        assert!(woken.0.swap(false, Ordering::SeqCst));
+        match follower.poll(&mut cx) {
+            Poll::Ready(Err(error)) => assert_eq!(*error, "down"),
+            _ => panic!("expected an error"),
+        }
+        assert!(!cache.contains_key(&2));
+        assert!(cache.flights.iter().all(|flights| flights.len() == 0));
+    }
+}
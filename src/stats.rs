@@ -0,0 +1,119 @@
+use std::ops::Add;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+
+/// A snapshot of what a cache has been doing, since statistics were enabled or last reset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Lookups which found the key cached.
+    pub hits: u64,
+    /// Lookups which didn't.
+    pub misses: u64,
+    /// Keys inserted which weren't cached yet. Replacing a value doesn't count.
+    pub inserts: u64,
+    /// Inserted keys which were found in the ghost queue, and so went straight to main.
+    pub ghost_hits: u64,
+    /// Entries moved from the small queue to the main queue.
+    pub promotions: u64,
+    /// Entries evicted from the small queue, leaving their key in the ghost queue.
+    pub demotions: u64,
+    /// Entries at the tail of the main queue which were given another round because they had
+    /// been accessed.
+    pub reinsertions: u64,
+    /// Entries evicted from the main queue.
+    pub evictions: u64,
+    /// Entries removed because they expired.
+    pub expirations: u64,
+    /// Current capacity of the small queue, which changes over time if the split is adaptive.
+    /// This is not a counter, and isn't reset.
+    pub small_size: usize,
+    /// Current capacity of the main queue.
+    pub main_size: usize,
+}
+
+impl Stats {
+    /// Fraction of lookups which were hits, or 0 if there weren't any.
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 / lookups as f64
+        }
+    }
+}
+
+impl Add for Stats {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            hits: self.hits + other.hits,
+            misses: self.misses + other.misses,
+            inserts: self.inserts + other.inserts,
+            ghost_hits: self.ghost_hits + other.ghost_hits,
+            promotions: self.promotions + other.promotions,
+            demotions: self.demotions + other.demotions,
+            reinsertions: self.reinsertions + other.reinsertions,
+            evictions: self.evictions + other.evictions,
+            expirations: self.expirations + other.expirations,
+            small_size: self.small_size + other.small_size,
+            main_size: self.main_size + other.main_size,
+        }
+    }
+}
+
+/// The live counters behind `Stats`. They are atomic since hits are counted through `&self`, but
+/// they don't order anything else, so relaxed operations are enough.
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub inserts: AtomicU64,
+    pub ghost_hits: AtomicU64,
+    pub promotions: AtomicU64,
+    pub demotions: AtomicU64,
+    pub reinsertions: AtomicU64,
+    pub evictions: AtomicU64,
+    pub expirations: AtomicU64,
+}
+
+impl Counters {
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            hits: self.hits.load(Relaxed),
+            misses: self.misses.load(Relaxed),
+            inserts: self.inserts.load(Relaxed),
+            ghost_hits: self.ghost_hits.load(Relaxed),
+            promotions: self.promotions.load(Relaxed),
+            demotions: self.demotions.load(Relaxed),
+            reinsertions: self.reinsertions.load(Relaxed),
+            evictions: self.evictions.load(Relaxed),
+            expirations: self.expirations.load(Relaxed),
+            small_size: 0,
+            main_size: 0,
+        }
+    }
+
+    pub fn reset(&self) {
+        for counter in [
+            &self.hits,
+            &self.misses,
+            &self.inserts,
+            &self.ghost_hits,
+            &self.promotions,
+            &self.demotions,
+            &self.reinsertions,
+            &self.evictions,
+            &self.expirations,
+        ] {
+            counter.store(0, Relaxed);
+        }
+    }
+}
+
+/// Add one to a counter.
+pub(crate) fn count(counter: &AtomicU64) {
+    counter.fetch_add(1, Relaxed);
+}
@@ -0,0 +1,69 @@
+use std::fmt;
+
+/// Computes how much of the cache's capacity an entry uses, for example its size in bytes.
+///
+/// The weight of an entry is computed when it is inserted or its value is replaced. Mutating a
+/// value in place, through `get_mut` or the entry API, does not re-weigh it.
+pub trait Weigher<K, V> {
+    fn weigh(&self, key: &K, value: &V) -> usize;
+}
+
+impl<K, V, F: Fn(&K, &V) -> usize> Weigher<K, V> for F {
+    fn weigh(&self, key: &K, value: &V) -> usize {
+        self(key, value)
+    }
+}
+
+/// Gives every entry a weight of 1, so capacities are simply entry counts.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnitWeigher;
+
+impl<K, V> Weigher<K, V> for UnitWeigher {
+    fn weigh(&self, _key: &K, _value: &V) -> usize {
+        1
+    }
+}
+
+/// An entry which was not inserted, and why. The key and value are handed back.
+pub struct Rejected<K, V> {
+    pub key: K,
+    pub value: V,
+    pub weight: usize,
+    pub reason: RejectionReason,
+}
+
+/// Why an entry was [`Rejected`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum RejectionReason {
+    /// It's heavier than the main queue's whole capacity, so it could never fit.
+    TooHeavy,
+    /// Pinned entries take up the room it needs, so it may fit once they're unpinned. See
+    /// [`S3Fifo::pin`](crate::S3Fifo::pin).
+    Pinned,
+}
+
+impl<K: fmt::Debug, V> fmt::Debug for Rejected<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rejected")
+            .field("key", &self.key)
+            .field("weight", &self.weight)
+            .field("reason", &self.reason)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K, V> fmt::Display for Rejected<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            RejectionReason::TooHeavy => {
+                write!(f, "entry of weight {} is larger than the cache", self.weight)
+            }
+            RejectionReason::Pinned => {
+                write!(f, "no room for entry of weight {} beside the pinned entries", self.weight)
+            }
+        }
+    }
+}
+
+impl<K: fmt::Debug, V> std::error::Error for Rejected<K, V> {}
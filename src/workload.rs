@@ -0,0 +1,277 @@
+//! Synthetic request streams, for tests, benchmarks and the simulator.
+//!
+//! Each workload generates an endless, reproducible stream of keys from a seed, so take as many
+//! as needed:
+//!
+//! ```
+//! use s3fifo::workload::Workload;
+//!
+//! let zipf = Workload::Zipf { keys: 1000, skew: 0.9 };
+//! let keys: Vec<u64> = zipf.generate(42).take(100).collect();
+//! assert!(keys.iter().all(|&key| key < 1000));
+//! ```
+
+// Keys which are only ever requested once are numbered from here, well clear of the others.
+const FRESH_KEYS: u64 = 1 << 63;
+
+/// A pattern of requests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum Workload {
+    /// Keys from 0 to `keys - 1`, where the key of rank `i` (starting from 1) is requested with
+    /// probability proportional to `1 / i^skew`. A skew of 0 is uniform, and the higher it is,
+    /// the more requests go to the most popular keys. Key 0 is the most popular.
+    Zipf { keys: u64, skew: f64 },
+    /// Like `Zipf`, but with a `fraction` of the requests going to keys which are never
+    /// requested again, which is what the small queue is there to filter out.
+    OneHitWonders { keys: u64, skew: f64, fraction: f64 },
+    /// Requests for a Zipfian hot set of `hot` keys, and every `period` requests, a sequential
+    /// scan of `scan` keys which haven't been seen before, and won't be again.
+    Scan { hot: u64, skew: f64, scan: u64, period: u64 },
+    /// Keys from 0 to `keys - 1` in order, over and over, which defeats LRU whenever `keys` is
+    /// larger than the cache.
+    Loop { keys: u64 },
+    /// Zipfian requests over a working set of `keys` keys, which is replaced by a new set of
+    /// keys every `period` requests.
+    Shifting { keys: u64, skew: f64, period: u64 },
+}
+
+impl Workload {
+    /// An endless stream of keys following the workload, always the same for the same seed.
+    pub fn generate(&self, seed: u64) -> KeyStream {
+        let zipf = match *self {
+            Workload::Zipf { keys, skew }
+            | Workload::OneHitWonders { keys, skew, .. }
+            | Workload::Shifting { keys, skew, .. } => Some(Zipf::new(keys, skew)),
+            Workload::Scan { hot, skew, .. } => Some(Zipf::new(hot, skew)),
+            Workload::Loop { .. } => None,
+        };
+        KeyStream {
+            workload: *self,
+            rng: SplitMix64(seed),
+            zipf,
+            position: 0,
+            fresh: FRESH_KEYS,
+        }
+    }
+}
+
+/// The keys of a workload, created by [`Workload::generate`].
+pub struct KeyStream {
+    workload: Workload,
+    rng: SplitMix64,
+    zipf: Option<Zipf>,
+    // Number of keys generated so far.
+    position: u64,
+    // Next key never seen before.
+    fresh: u64,
+}
+
+impl KeyStream {
+    fn zipf(&mut self) -> u64 {
+        let zipf = self.zipf.as_ref().expect("workload is zipfian");
+        zipf.sample(&mut self.rng)
+    }
+
+    fn fresh(&mut self) -> u64 {
+        self.fresh += 1;
+        self.fresh - 1
+    }
+}
+
+impl Iterator for KeyStream {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let position = self.position;
+        self.position += 1;
+        Some(match self.workload {
+            Workload::Zipf { .. } => self.zipf(),
+            Workload::OneHitWonders { fraction, .. } => {
+                if self.rng.next_f64() < fraction {
+                    self.fresh()
+                } else {
+                    self.zipf()
+                }
+            }
+            Workload::Scan { scan, period, .. } => {
+                // Each period starts with the scan, then goes back to the hot set.
+                if position % period.max(1) < scan {
+                    self.fresh()
+                } else {
+                    self.zipf()
+                }
+            }
+            Workload::Loop { keys } => position % keys.max(1),
+            Workload::Shifting { keys, period, .. } => {
+                keys * (position / period.max(1)) + self.zipf()
+            }
+        })
+    }
+}
+
+/// The SplitMix64 generator, which is small and fast, and good enough for generating workloads.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1).
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Samples Zipf distributed ranks in constant time and space, by rejection-inversion, from
+/// "Rejection-Inversion to Generate Variates from Monotone Discrete Distributions" by Wolfgang
+/// Hörmann and Gerhard Derflinger.
+struct Zipf {
+    n: f64,
+    skew: f64,
+    h_integral_x1: f64,
+    h_integral_n: f64,
+    s: f64,
+}
+
+impl Zipf {
+    fn new(n: u64, skew: f64) -> Self {
+        let mut zipf = Self {
+            n: n.max(1) as f64,
+            skew,
+            h_integral_x1: 0.0,
+            h_integral_n: 0.0,
+            s: 0.0,
+        };
+        zipf.h_integral_x1 = zipf.h_integral(1.5) - 1.0;
+        zipf.h_integral_n = zipf.h_integral(zipf.n + 0.5);
+        zipf.s = 2.0 - zipf.h_integral_inverse(zipf.h_integral(2.5) - zipf.h(2.0));
+        zipf
+    }
+
+    /// A key from 0 to n - 1, where 0 is the most likely.
+    fn sample(&self, rng: &mut SplitMix64) -> u64 {
+        loop {
+            let u = self.h_integral_n + rng.next_f64() * (self.h_integral_x1 - self.h_integral_n);
+            let x = self.h_integral_inverse(u);
+            let k = (x + 0.5).floor().clamp(1.0, self.n);
+            if k - x <= self.s || u >= self.h_integral(k + 0.5) - self.h(k) {
+                return k as u64 - 1;
+            }
+        }
+    }
+
+    fn h(&self, x: f64) -> f64 {
+        (-self.skew * x.ln()).exp()
+    }
+
+    fn h_integral(&self, x: f64) -> f64 {
+        let log_x = x.ln();
+        helper2((1.0 - self.skew) * log_x) * log_x
+    }
+
+    fn h_integral_inverse(&self, x: f64) -> f64 {
+        let t = (x * (1.0 - self.skew)).max(-1.0);
+        (helper1(t) * x).exp()
+    }
+}
+
+/// `ln(1 + x) / x`, accurate near 0.
+fn helper1(x: f64) -> f64 {
+    if x.abs() > 1e-8 {
+        x.ln_1p() / x
+    } else {
+        1.0 - x * (0.5 - x * (1.0 / 3.0 - 0.25 * x))
+    }
+}
+
+/// `(e^x - 1) / x`, accurate near 0.
+fn helper2(x: f64) -> f64 {
+    if x.abs() > 1e-8 {
+        x.exp_m1() / x
+    } else {
+        1.0 + x * 0.5 * (1.0 + x / 3.0 * (1.0 + 0.25 * x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::{CachePolicy, LruCache};
+    use crate::S3Fifo;
+
+    fn counts(workload: Workload, n: usize) -> Vec<usize> {
+        let mut counts = vec![0; 10];
+        for key in workload.generate(0).take(n) {
+            if let Some(count) = counts.get_mut(key as usize) {
+                *count += 1;
+            }
+        }
+        counts
+    }
+
+    fn miss_ratio(cache: &mut impl CachePolicy<u64, ()>, keys: impl Iterator<Item = u64>) -> f64 {
+        let (mut requests, mut misses) = (0, 0);
+        for key in keys {
+            requests += 1;
+            if cache.get(&key).is_none() {
+                misses += 1;
+                cache.insert(key, ());
+            }
+        }
+        misses as f64 / requests as f64
+    }
+
+    #[test]
+    fn zipf() {
+        // With a skew of 1, key i is requested in proportion to 1 / (i + 1).
+        let skewed = counts(Workload::Zipf { keys: 10, skew: 1.0 }, 100_000);
+        let total: f64 = (1 ..= 10).map(|i| 1.0 / i as f64).sum();
+        for (i, &count) in skewed.iter().enumerate() {
+            let expected = 100_000.0 / (i + 1) as f64 / total;
+            assert!((count as f64 - expected).abs() < expected * 0.05, "{i}: {count}");
+        }
+        let uniform = counts(Workload::Zipf { keys: 10, skew: 0.0 }, 100_000);
+        assert!(uniform.iter().all(|&count| (9_500 .. 10_500).contains(&count)), "{uniform:?}");
+
+        let skewed = Workload::Zipf { keys: 1 << 40, skew: 1.2 };
+        assert_eq!(skewed.generate(7).take(1000).count(), 1000);
+        let a: Vec<_> = skewed.generate(7).take(100).collect();
+        assert_eq!(a, skewed.generate(7).take(100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn patterns() {
+        let keys: Vec<_> = Workload::Loop { keys: 3 }.generate(0).take(7).collect();
+        assert_eq!(keys, [0, 1, 2, 0, 1, 2, 0]);
+
+        let scan = Workload::Scan { hot: 5, skew: 0.5, scan: 3, period: 10 };
+        let keys: Vec<_> = scan.generate(0).take(20).collect();
+        assert_eq!(&keys[.. 3], [FRESH_KEYS, FRESH_KEYS + 1, FRESH_KEYS + 2]);
+        assert!(keys[3 .. 10].iter().all(|&key| key < 5));
+        assert_eq!(keys[10], FRESH_KEYS + 3);
+
+        let shifting = Workload::Shifting { keys: 10, skew: 0.8, period: 100 };
+        let keys: Vec<_> = shifting.generate(0).take(300).collect();
+        assert!(keys[.. 100].iter().all(|&key| key < 10));
+        assert!(keys[200 ..].iter().all(|&key| (20 .. 30).contains(&key)));
+
+        let wonders = Workload::OneHitWonders { keys: 100, skew: 1.0, fraction: 0.3 };
+        let fresh = wonders.generate(0).take(10_000).filter(|&key| key >= FRESH_KEYS).count();
+        assert!((2_800 .. 3_200).contains(&fresh), "{fresh}");
+    }
+
+    #[test]
+    fn s3fifo_filters_one_hit_wonders() {
+        let wonders = Workload::OneHitWonders { keys: 10_000, skew: 1.0, fraction: 0.5 };
+        let keys = || wonders.generate(1).take(200_000);
+        let s3fifo = miss_ratio(&mut S3Fifo::new(100, 900), keys());
+        let lru = miss_ratio(&mut LruCache::new(1000), keys());
+        assert!(s3fifo < lru, "{s3fifo} >= {lru}");
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::Hash;
+
+use crate::S3Fifo;
+
+/// Writes changes to the store behind a cache, for example a key-value store. See
+/// [`WritingCache`].
+pub trait CacheWriter<K, V> {
+    type Error;
+
+    fn write(&self, key: &K, value: &V) -> Result<(), Self::Error>;
+
+    fn delete(&self, key: &K) -> Result<(), Self::Error>;
+}
+
+/// When a [`WritingCache`] writes inserted values to its store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WriteMode {
+    /// On `insert`, before the value is cached, so the cache never holds a value the store
+    /// doesn't.
+    Through,
+    /// Once the value leaves the cache, by being evicted or expiring, or on `flush`. Until then,
+    /// the entry is dirty, and only the cache has its value.
+    Back,
+}
+
+/// A key whose value couldn't be written to the store by [`WritingCache::flush`].
+pub struct FlushError<K, E> {
+    pub key: K,
+    pub error: E,
+}
+
+impl<K: fmt::Debug, E: fmt::Debug> fmt::Debug for FlushError<K, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FlushError").field("key", &self.key).field("error", &self.error).finish()
+    }
+}
+
+impl<K, E: fmt::Display> fmt::Display for FlushError<K, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to write entry: {}", self.error)
+    }
+}
+
+impl<K: fmt::Debug, E: fmt::Debug + fmt::Display> std::error::Error for FlushError<K, E> {}
+
+/// A cache in front of a store, which writes inserted values to the store with a
+/// [`CacheWriter`], either straight away or once they leave the cache, as set by the
+/// [`WriteMode`]. Removing a key always deletes it from the store straight away.
+///
+/// In write-back mode, a dirty entry which fails to be written when it's evicted is held on to,
+/// and still returned by `get`, until a `flush` manages to write it. `flush` reports every
+/// entry it couldn't write; dropping the cache flushes it too, but can only ignore failures, so
+/// flush first to find out about them.
+pub struct WritingCache<K: Hash + Eq + Clone, V, W: CacheWriter<K, V>> {
+    cache: S3Fifo<K, V>,
+    writer: W,
+    mode: WriteMode,
+    // Cached keys whose value hasn't been written yet.
+    dirty: HashSet<K>,
+    // Dirty entries which left the cache, but failed to be written.
+    unwritten: HashMap<K, V>,
+}
+
+impl<K: Hash + Eq + Clone, V, W: CacheWriter<K, V>> WritingCache<K, V, W> {
+    pub fn new(mut cache: S3Fifo<K, V>, writer: W, mode: WriteMode) -> Self {
+        if mode == WriteMode::Back {
+            cache.keep_evicted();
+        }
+        Self {
+            cache,
+            writer,
+            mode,
+            dirty: HashSet::new(),
+            unwritten: HashMap::new(),
+        }
+    }
+
+    /// Look up a key, counting it as an access.
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.cache.read(key).or_else(|| self.unwritten.get(key))
+    }
+
+    /// Insert a value, writing it to the store first in write-through mode, and returning the
+    /// previous one. If the write fails, the cache is left unchanged.
+    ///
+    /// In write-back mode, a value which can't be cached, because it's too heavy or pinned entries
+    /// are in the way, is written straight away.
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, W::Error> {
+        if self.mode == WriteMode::Through {
+            self.writer.write(&key, &value)?;
+            return Ok(self.cache.insert(key, value));
+        }
+        let unwritten = self.unwritten.remove(&key);
+        let old = match self.cache.try_insert(key.clone(), value) {
+            Ok(old) => {
+                self.write_evicted();
+                // Unless making room evicted the entry itself, in which case it was just written.
+                if self.cache.index.contains_key(&key) {
+                    self.dirty.insert(key);
+                }
+                old
+            }
+            Err(rejected) => {
+                if let Err(error) = self.writer.write(&rejected.key, &rejected.value) {
+                    if let Some(value) = unwritten {
+                        self.unwritten.insert(key, value);
+                    }
+                    return Err(error);
+                }
+                self.dirty.remove(&key);
+                self.cache.remove(&key)
+            }
+        };
+        Ok(old.or(unwritten))
+    }
+
+    /// Delete a key from the store, and then remove it from the cache. If the delete fails,
+    /// the cache is left unchanged.
+    pub fn remove(&mut self, key: &K) -> Result<Option<V>, W::Error> {
+        self.writer.delete(key)?;
+        self.dirty.remove(key);
+        let unwritten = self.unwritten.remove(key);
+        Ok(self.cache.remove(key).or(unwritten))
+    }
+
+    /// Write every dirty entry to the store, and retry those which failed to be written when
+    /// they were evicted. Entries which still can't be written stay dirty, and are returned
+    /// with their errors.
+    ///
+    /// Does nothing in write-through mode.
+    pub fn flush(&mut self) -> Result<(), Vec<FlushError<K, W::Error>>> {
+        // Expired entries are written as they're reclaimed.
+        self.cache.remove_expired();
+        self.write_evicted();
+        let mut failed = vec![];
+        for (key, value) in std::mem::take(&mut self.unwritten) {
+            if let Err(error) = self.writer.write(&key, &value) {
+                failed.push(FlushError { key: key.clone(), error });
+                self.unwritten.insert(key, value);
+            }
+        }
+        let mut dirty = HashSet::new();
+        for key in std::mem::take(&mut self.dirty) {
+            // Dirty entries which left the cache were written, or put in `unwritten`, as they
+            // left, so there's nothing more to do for one which isn't here.
+            let Some(&idx) = self.cache.index.get(&key) else { continue };
+            if let Err(error) = self.writer.write(&key, &self.cache.slot(idx).value) {
+                failed.push(FlushError { key: key.clone(), error });
+                dirty.insert(key);
+            }
+        }
+        self.dirty = dirty;
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(failed)
+        }
+    }
+
+    /// Number of entries whose value hasn't been written to the store yet.
+    pub fn unwritten(&self) -> usize {
+        self.dirty.len() + self.unwritten.len()
+    }
+
+    pub fn mode(&self) -> WriteMode {
+        self.mode
+    }
+
+    /// The underlying cache, to read its statistics, or look up keys without counting it.
+    pub fn cache(&self) -> &S3Fifo<K, V> {
+        &self.cache
+    }
+
+    pub fn writer(&self) -> &W {
+        &self.writer
+    }
+
+    /// Write the dirty entries which were evicted or expired since last time.
+    fn write_evicted(&mut self) {
+        for (key, value) in self.cache.take_evicted() {
+            if self.dirty.remove(&key) && self.writer.write(&key, &value).is_err() {
+                // Reported by the next flush, which tries again.
+                self.unwritten.insert(key, value);
+            }
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V, W: CacheWriter<K, V>> Drop for WritingCache<K, V, W> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+
+    #[derive(Default)]
+    struct Store<V> {
+        values: RefCell<HashMap<u32, V>>,
+        writes: Cell<usize>,
+        down: Cell<bool>,
+    }
+
+    impl<V: Clone> CacheWriter<u32, V> for &Store<V> {
+        type Error = &'static str;
+
+        fn write(&self, key: &u32, value: &V) -> Result<(), &'static str> {
+            if self.down.get() {
+                return Err("down");
+            }
+            self.writes.set(self.writes.get() + 1);
+            self.values.borrow_mut().insert(*key, value.clone());
+            Ok(())
+        }
+
+        fn delete(&self, key: &u32) -> Result<(), &'static str> {
+            if self.down.get() {
+                return Err("down");
+            }
+            self.values.borrow_mut().remove(key);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_through() {
+        let store = Store::<u32>::default();
+        let mut cache = WritingCache::new(S3Fifo::new(1, 2), &store, WriteMode::Through);
+        assert_eq!(cache.insert(1, 10), Ok(None));
+        assert_eq!(store.values.borrow()[&1], 10);
+        store.down.set(true);
+        assert_eq!(cache.insert(1, 11), Err("down"));
+        assert_eq!(cache.remove(&1), Err("down"));
+        assert_eq!(cache.get(&1), Some(&10));
+        store.down.set(false);
+        assert_eq!(cache.remove(&1), Ok(Some(10)));
+        assert!(store.values.borrow().is_empty());
+        assert_eq!(cache.flush().map_err(|failed| failed.len()), Ok(()));
+        assert_eq!(cache.unwritten(), 0);
+    }
+
+    #[test]
+    fn write_back() {
+        let store = Store::<u32>::default();
+        let mut cache = WritingCache::new(S3Fifo::new(1, 2), &store, WriteMode::Back);
+        cache.insert(1, 10).unwrap();
+        cache.insert(1, 11).unwrap();
+        assert!(store.values.borrow().is_empty());
+        // Evicting 1 writes its latest value.
+        cache.insert(2, 20).unwrap();
+        assert_eq!(store.values.borrow().get(&1), Some(&11));
+        assert_eq!((store.writes.get(), cache.unwritten()), (1, 1));
+
+        // A failed write on eviction is kept until a flush gets through.
+        store.down.set(true);
+        cache.insert(3, 30).unwrap();
+        assert_eq!(cache.get(&2), Some(&20));
+        assert!(!cache.cache().contains_key(&2));
+        let failed = cache.flush().unwrap_err();
+        let mut keys: Vec<_> = failed.iter().map(|failed| failed.key).collect();
+        keys.sort();
+        assert_eq!(keys, [2, 3]);
+        assert_eq!(cache.unwritten(), 2);
+        store.down.set(false);
+        cache.flush().unwrap();
+        assert_eq!(cache.unwritten(), 0);
+        assert_eq!(store.values.borrow()[&2], 20);
+
+        // Removing deletes from the store straight away, and dropping flushes.
+        assert_eq!(cache.remove(&3), Ok(Some(30)));
+        cache.insert(4, 40).unwrap();
+        assert!(!store.values.borrow().contains_key(&3));
+        drop(cache);
+        assert_eq!(store.values.borrow()[&4], 40);
+    }
+
+    #[test]
+    fn growing_values() {
+        let store = Store::<Vec<u8>>::default();
+        let inner = S3Fifo::with_weigher(10, 100, |_: &u32, v: &Vec<u8>| v.len());
+        let mut cache = WritingCache::new(inner, &store, WriteMode::Back);
+        cache.insert(1, vec![0; 5]).unwrap();
+        cache.insert(2, vec![0; 5]).unwrap();
+        // Growing 1 evicts 2, which gets written.
+        assert_eq!(cache.insert(1, vec![0; 8]), Ok(Some(vec![0; 5])));
+        assert_eq!(store.values.borrow().get(&2).map(Vec::len), Some(5));
+        assert_eq!(cache.unwritten(), 1);
+        cache.flush().unwrap();
+        assert_eq!(store.values.borrow().get(&1).map(Vec::len), Some(8));
+        cache.insert(1, vec![0; 12]).unwrap();
+        drop(cache);
+        assert_eq!(store.values.borrow().get(&1).map(Vec::len), Some(12));
+    }
+}